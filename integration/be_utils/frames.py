@@ -0,0 +1,70 @@
+'''Helpers to build and read v2 protocol frames'''
+import struct
+import zlib
+
+MAGIC = b"\xbe\xef"
+VERSION_2 = 2
+
+FRAME_TYPE_TELEMETRY = 0x01
+FRAME_TYPE_ACK = 0x81
+FRAME_TYPE_NACK = 0x82
+FRAME_TYPE_NO_CONFIG = 0x83
+FRAME_TYPE_COMMAND = 0x84
+FRAME_TYPE_COMMAND_ACK = 0x05
+FRAME_TYPE_FIRMWARE_STATUS = 0x06
+FRAME_TYPE_FIRMWARE_REQUEST = 0x07
+FRAME_TYPE_FIRMWARE_OFFER = 0x85
+FRAME_TYPE_FIRMWARE_CHUNK = 0x86
+FRAME_TYPE_JSON_DOCUMENT = 0x08
+FRAME_TYPE_JSON_RESPONSE = 0x88
+
+
+def encode(frame_type: int, payload: bytes) -> bytes:
+    '''Wrap payload into v2 envelope'''
+    body = struct.pack("<BBH", VERSION_2, frame_type, len(payload)) + payload
+    return MAGIC + body + struct.pack("<I", zlib.crc32(body))
+
+
+def telemetry(informer_id: int, readings: list) -> bytes:
+    '''v2 telemetry frame from list of (temperature, humidity)'''
+    payload = struct.pack("<BB", informer_id, len(readings))
+    for temperature, humidity in readings:
+        payload += struct.pack("<ff", temperature, humidity)
+    return encode(FRAME_TYPE_TELEMETRY, payload)
+
+
+def command_ack(command_ids: list) -> bytes:
+    '''v2 frame confirming executed commands'''
+    payload = struct.pack("<B", len(command_ids))
+    for command_id in command_ids:
+        payload += struct.pack("<Q", command_id)
+    return encode(FRAME_TYPE_COMMAND_ACK, payload)
+
+
+def firmware_status(device_id: int, version: int) -> bytes:
+    '''v2 frame reporting running firmware version'''
+    return encode(FRAME_TYPE_FIRMWARE_STATUS, struct.pack("<QI", device_id, version))
+
+
+def firmware_request(device_id: int, version: int, offset: int, length: int) -> bytes:
+    '''v2 frame asking for a firmware chunk'''
+    return encode(FRAME_TYPE_FIRMWARE_REQUEST, struct.pack("<QIIH", device_id, version, offset, length))
+
+
+def read_frame(sock):
+    '''Read one v2 frame from socket, returns (frame_type, payload)'''
+    def read_exact(size):
+        data = b""
+        while len(data) < size:
+            chunk = sock.recv(size - len(data))
+            if not chunk:
+                raise ConnectionError("Connection closed")
+            data += chunk
+        return data
+    header = read_exact(6)
+    assert header[:2] == MAGIC
+    _, frame_type, length = struct.unpack("<BBH", header[2:])
+    payload = read_exact(length)
+    checksum = struct.unpack("<I", read_exact(4))[0]
+    assert checksum == zlib.crc32(header[2:] + payload)
+    return frame_type, payload
@@ -3,9 +3,12 @@
 # pylint:disable=redefined-outer-name
 
 
+import signal
+import subprocess
 from pathlib import Path
 import pytest
 import be_utils.common as common_utils # pylint: disable=E0401
+import be_utils.be_server as be_server_helper # pylint: disable=E0401
 
 pytest_plugins = ['mosquito_fixtures', 'postgres_fixtures']
 
@@ -21,3 +24,51 @@ def data_path():
 def be_service_port():
     """return avaliable port for BE Service"""
     return common_utils.get_free_port()
+
+
+@pytest.fixture
+def be_server(
+        postgres_container,
+        postgres_username,
+        postgress_password,
+        postgress_port,
+        postgress_database_name,
+        mosquitto_container,
+        mosquitto_mqtt_port,
+        mosquitto_username,
+        mosquitto_password,
+        be_service_port):
+    """Starts BE Server against the test Postgres and Mosquitto with extra
+    command line arguments, returns the process and its listener port.
+    Servers still running are stopped after the test"""
+    processes = []
+
+    def start(*extra_args, topic="topic.session"):
+        port = common_utils.get_free_port()
+        run_params = [
+            "target/debug/be-server",
+            "--lport", f"{port}",
+            "--lhost", "127.0.0.1",
+            "--mhost", "127.0.0.1",
+            "--mport", f"{mosquitto_mqtt_port}",
+            "--muser", f"{mosquitto_username}",
+            "--mpassword", f"{mosquitto_password}",
+            "--mtopic", topic,
+            "--sport", f"{be_service_port}",
+            "--plogin", f"{postgres_username}",
+            "--ppassword", f"{postgress_password}",
+            "--pport", f"{postgress_port}",
+            "--phost", "127.0.0.1",
+            "--pdbname", f"{postgress_database_name}",
+            *extra_args,
+        ]
+        process = subprocess.Popen(run_params)
+        processes.append(process)
+        be_server_helper.wait_till_service_start(be_service_port, 10)
+        return process, port
+
+    yield start
+    for process in processes:
+        if process.poll() is None:
+            process.send_signal(signal.SIGINT)
+            process.wait()
@@ -129,6 +129,8 @@ def test(
     }]
 
     devices_objects = [json.loads(device) for device in devices]
+    for device in devices_objects:
+        assert device.pop("received_at") > 0, "server stamps every reading"
     assert len(devices_objects) == len(elements)
     elements.sort(key = lambda x: x["id"])
     devices_objects.sort(key = lambda x: x["id"])
@@ -1,54 +1,14 @@
 import random
 import socket
 import struct
-import subprocess
 import psycopg
 from be_utils import postgres
-import be_utils.be_server as be_server_helper #pylint: disable=E0401
+from be_utils import frames
 import pytest
 
-def test(
-        # POSTGRES
-        postgres_container,
-        postgres_connection_string,
-        postgres_username,
-        postgress_password,
-        postgress_port,
-        postgress_database_name,
-
-        # MQTT
-        mosquitto_container,
-        mosquitto_mqtt_port,
-        mosquitto_username,
-        mosquitto_password,
-        
-        # BE
-        be_service_port):
-    #   SETUP
+def test(postgres_connection_string, be_server):
     connection = psycopg.connect(postgres_connection_string, autocommit=True)
-    port = random.randint(30000, 32000)
-    run_params =  [
-            "target/debug/be-server",
-            "--lport", f"{port}",
-            "--lhost", "127.0.0.1",
-            "--mhost", "127.0.0.1",
-            "--mport", f"{mosquitto_mqtt_port}",
-            "--muser", f"{mosquitto_username}",
-            "--mpassword", f"{mosquitto_password}",
-            "--mtopic", f"topic.1",
-            "--sport", f"{be_service_port}",
-            "--plogin", f"{postgres_username}",
-            "--ppassword", f"{postgress_password}",
-            "--pport", f"{postgress_port}",
-            "--phost", "127.0.0.1",
-            "--pdbname", f"{postgress_database_name}"
-
-    ]
-    pe_process = subprocess.Popen(
-        run_params
-    )
-    be_server_helper.wait_till_service_start(be_service_port, 10)
-    #   END SETUP
+    _, port = be_server(topic="topic.1")
     postgres.set_frige_config(connection, 101, {'temperature': 3, 'humidity': 10})
     postgres.set_frige_config(connection, 102, {'temperature': 4, 'humidity': 11})
     buf = []
@@ -77,11 +37,41 @@ def test(
 
 
     s.close()
-    pe_process.send_signal(2)    
     assert id1 == 101
     assert temperature1 == 3
     assert humidity1 == 10
 
     assert id2 == 102
     assert temperature2 == 4
-    assert humidity2 == 11
\ No newline at end of file
+    assert humidity2 == 11
+
+
+def test_firmware_chunks(postgres_connection_string, be_server):
+    '''Firmware chunks are read from Postgres at any offset'''
+    connection = psycopg.connect(postgres_connection_string, autocommit=True)
+    _, port = be_server(topic="topic.1")
+    postgres.set_frige_config(connection, 1101, {'temperature': 3, 'humidity': 10})
+    image = bytes(range(256)) * 3
+    connection.execute(
+        "INSERT INTO Firmware (type, version, image) VALUES ('fridge', 5, %s) "
+        "ON CONFLICT (type) DO UPDATE SET version = EXCLUDED.version, image = EXCLUDED.image",
+        (image,))
+
+    s = socket.socket(socket.AF_INET)
+    s.connect(("127.0.0.1", port))
+    s.send(frames.firmware_request(1101, 5, 300, 100))
+    middle_type, middle = frames.read_frame(s)
+    s.send(frames.firmware_request(1101, 5, 700, 100))
+    last_type, last = frames.read_frame(s)
+    s.send(frames.firmware_request(1101, 5, 2000, 100))
+    past_end_type, past_end = frames.read_frame(s)
+
+    s.close()
+
+    assert middle_type == frames.FRAME_TYPE_FIRMWARE_CHUNK
+    assert struct.unpack("<II", middle[:8]) == (5, 300)
+    assert middle[8:] == image[300:400]
+    assert last_type == frames.FRAME_TYPE_FIRMWARE_CHUNK
+    assert last[8:] == image[700:], "last chunk is shorter"
+    assert past_end_type == frames.FRAME_TYPE_FIRMWARE_CHUNK
+    assert past_end[8:] == b""
@@ -0,0 +1,258 @@
+import hashlib
+import json
+import random
+import socket
+import struct
+import time
+import psycopg
+import requests
+from be_utils import postgres
+from be_utils import frames
+
+
+def _make_frame(informer_id: int, temperature: float, humidity: float):
+    buf = [informer_id, 1]
+    buf += struct.pack("f", temperature)
+    buf += struct.pack("f", humidity)
+    return bytearray(buf)
+
+
+def _read_target(s: socket.socket):
+    rcv = b""
+    while len(rcv) < 12:
+        rcv += s.recv(12 - len(rcv))
+    device_id = struct.unpack("i", rcv[:4])[0]
+    humidity = struct.unpack("f", rcv[4:8])[0]
+    temperature = struct.unpack("f", rcv[8:12])[0]
+    return device_id, humidity, temperature
+
+
+def test(postgres_connection_string, be_server):
+    '''Several frames over one connection, including split and coalesced ones,
+    while another connection is served concurrently'''
+    connection = psycopg.connect(postgres_connection_string, autocommit=True)
+    _, port = be_server("--ltimeout", "2")
+    postgres.set_frige_config(connection, 301, {'temperature': 5, 'humidity': 20})
+
+    s = socket.socket(socket.AF_INET)
+    s.connect(("127.0.0.1", port))
+
+    frame = _make_frame(3, random.random(), random.random())
+    s.send(frame)
+    first = _read_target(s)
+
+    s.send(frame[:5])
+    s.send(frame[5:])
+    second = _read_target(s)
+
+    s.send(frame + frame)
+    third = _read_target(s)
+    fourth = _read_target(s)
+
+    other = socket.socket(socket.AF_INET)
+    other.connect(("127.0.0.1", port))
+    other.send(frame)
+    concurrent = _read_target(other)
+    other.close()
+
+    s.settimeout(5)
+    closed_by_server = s.recv(1) == b""
+
+    s.close()
+    for target in [first, second, third, fourth, concurrent]:
+        assert target == (301, 20, 5)
+    assert closed_by_server, "idle connection is closed by server"
+
+
+def test_v2_responses(postgres_connection_string, be_server):
+    '''v2 informers get ACK, NO_CONFIG and NACK frames'''
+    connection = psycopg.connect(postgres_connection_string, autocommit=True)
+    _, port = be_server()
+    postgres.set_frige_config(connection, 401, {'temperature': 6, 'humidity': 30})
+
+    s = socket.socket(socket.AF_INET)
+    s.connect(("127.0.0.1", port))
+
+    s.send(frames.telemetry(4, [(1.5, 40), (2.5, 50)]))
+    ack_type, ack = frames.read_frame(s)
+    no_config_type, no_config = frames.read_frame(s)
+
+    broken = bytearray(frames.telemetry(4, [(1.5, 40)]))
+    broken[8] ^= 0xff
+    s.send(broken)
+    nack_type, nack = frames.read_frame(s)
+
+    s.close()
+
+    assert ack_type == frames.FRAME_TYPE_ACK
+    assert ack[0] == 1
+    assert struct.unpack("<iff", ack[1:13]) == (401, 30, 6)
+
+    assert no_config_type == frames.FRAME_TYPE_NO_CONFIG
+    assert no_config[0] == 1
+    assert struct.unpack("<Q", no_config[1:9])[0] == 402
+
+    assert nack_type == frames.FRAME_TYPE_NACK
+    assert nack == bytes([4]), "bad checksum code"
+
+
+def test_json_documents(postgres_connection_string, be_server):
+    '''Gateways sending JSON documents get JSON responses'''
+    connection = psycopg.connect(postgres_connection_string, autocommit=True)
+    _, port = be_server()
+    postgres.set_frige_config(connection, 501, {'temperature': 7, 'humidity': 35})
+
+    s = socket.socket(socket.AF_INET)
+    s.connect(("127.0.0.1", port))
+
+    document = {"informer": 5, "devices": [
+        {"sensor": 1, "temperature": 1.5, "humidity": 40},
+        {"sensor": 2, "temperature": 2.5, "humidity": 50},
+    ]}
+    s.send(frames.encode(frames.FRAME_TYPE_JSON_DOCUMENT, json.dumps(document).encode()))
+    response_type, response = frames.read_frame(s)
+
+    s.send(frames.encode(frames.FRAME_TYPE_JSON_DOCUMENT, b'{"informer": 5, "devices": [{"sensor": 1, "pressure": 1}]}'))
+    error_type, error = frames.read_frame(s)
+
+    s.close()
+
+    assert response_type == frames.FRAME_TYPE_JSON_RESPONSE
+    assert json.loads(response) == {
+        "targets": [{"id": 501, "sensor": 1, "temperature": 7, "humidity": 35}],
+        "no_config": [502],
+    }
+    assert error_type == frames.FRAME_TYPE_JSON_RESPONSE
+    assert json.loads(error)["code"] == 9, "bad document code"
+
+
+def test_commands(postgres_connection_string, be_server, be_service_port):
+    '''Commands queued over HTTP are delivered after targets and acknowledged'''
+    connection = psycopg.connect(postgres_connection_string, autocommit=True)
+    _, port = be_server()
+    postgres.set_frige_config(connection, 601, {'temperature': 6, 'humidity': 30})
+    created = requests.post(
+        f"http://localhost:{be_service_port}/devices/601/commands",
+        data=json.dumps({"command": "set_reporting_interval", "seconds": 60}),
+        timeout=5,
+    )
+
+    s = socket.socket(socket.AF_INET)
+    s.connect(("127.0.0.1", port))
+    s.send(frames.telemetry(6, [(1.5, 40)]))
+    ack_type, _ = frames.read_frame(s)
+    command_type, command = frames.read_frame(s)
+    delivered = connection.execute(
+        "SELECT status FROM DeviceCommand WHERE id = %s", (created.json()["id"],)).fetchone()[0]
+
+    s.send(frames.command_ack([created.json()["id"]]))
+    command_ack_type, _ = frames.read_frame(s)
+    acknowledged = connection.execute(
+        "SELECT status FROM DeviceCommand WHERE id = %s", (created.json()["id"],)).fetchone()[0]
+
+    s.close()
+
+    assert created.status_code == 201
+    assert ack_type == frames.FRAME_TYPE_ACK
+    assert command_type == frames.FRAME_TYPE_COMMAND
+    assert struct.unpack("<QQB", command[:17]) == (601, created.json()["id"], 0x03)
+    assert struct.unpack("<I", command[17:])[0] == 60
+    assert delivered == "delivered"
+    assert command_ack_type == frames.FRAME_TYPE_ACK
+    assert acknowledged == "acknowledged"
+
+
+def test_firmware(postgres_connection_string, be_server):
+    '''Firmware is offered to outdated devices and streamed in chunks,
+    a transfer resumes from the offset of a new connection'''
+    connection = psycopg.connect(postgres_connection_string, autocommit=True)
+    _, port = be_server()
+    postgres.set_frige_config(connection, 701, {'temperature': 6, 'humidity': 30})
+    image = bytes(range(256)) * 10
+    connection.execute(
+        "INSERT INTO Firmware (type, version, image) VALUES ('fridge', 8, %s) "
+        "ON CONFLICT (type) DO UPDATE SET version = EXCLUDED.version, image = EXCLUDED.image",
+        (image,))
+
+    s = socket.socket(socket.AF_INET)
+    s.connect(("127.0.0.1", port))
+    s.send(frames.firmware_status(701, 7))
+    offer_type, offer = frames.read_frame(s)
+    s.send(frames.firmware_request(701, 8, 0, 1000))
+    _, first = frames.read_frame(s)
+    s.close()
+
+    # Interrupted after the first chunk, the next connection continues from there
+    received = first[8:]
+    s = socket.socket(socket.AF_INET)
+    s.connect(("127.0.0.1", port))
+    while len(received) < len(image):
+        s.send(frames.firmware_request(701, 8, len(received), 1000))
+        _, chunk = frames.read_frame(s)
+        assert struct.unpack("<II", chunk[:8]) == (8, len(received))
+        received += chunk[8:]
+    s.send(frames.firmware_request(701, 7, 0, 1000))
+    outdated_type, _ = frames.read_frame(s)
+    s.send(frames.firmware_status(701, 8))
+    updated_type, _ = frames.read_frame(s)
+    reported = connection.execute(
+        "SELECT version FROM DeviceFirmware WHERE device_id = 701").fetchone()[0]
+
+    s.close()
+
+    assert offer_type == frames.FRAME_TYPE_FIRMWARE_OFFER
+    assert struct.unpack("<II", offer[:8]) == (8, len(image))
+    assert offer[8:] == hashlib.sha256(image).digest()
+    assert hashlib.sha256(received).digest() == offer[8:]
+    assert outdated_type == frames.FRAME_TYPE_NACK, "chunks of another version are refused"
+    assert updated_type == frames.FRAME_TYPE_ACK
+    assert reported == 8
+
+
+def test_config_cache(postgres_connection_string, be_server):
+    '''Configs from Postgres are cached by device id until their lifetime ends'''
+    connection = psycopg.connect(postgres_connection_string, autocommit=True)
+    _, port = be_server("--cttl", "2")
+    postgres.set_frige_config(connection, 801, {'temperature': 5, 'humidity': 20})
+
+    s = socket.socket(socket.AF_INET)
+    s.connect(("127.0.0.1", port))
+    s.send(_make_frame(8, 1.5, 40))
+    first = _read_target(s)
+    connection.execute(
+        "UPDATE DeviceConfig SET config = %s WHERE id = 801",
+        (json.dumps({'temperature': 3, 'humidity': 25}),))
+    s.send(_make_frame(8, 1.5, 40))
+    cached = _read_target(s)
+    time.sleep(2.5)
+    s.send(_make_frame(8, 1.5, 40))
+    refreshed = _read_target(s)
+
+    s.close()
+
+    assert first == (801, 20, 5)
+    assert cached == (801, 20, 5), "config is served from cache"
+    assert refreshed == (801, 25, 3), "expired config is read again"
+
+
+def test_config_notify(postgres_connection_string, be_server):
+    '''Changed DeviceConfig rows are dropped from the cache before their lifetime ends'''
+    connection = psycopg.connect(postgres_connection_string, autocommit=True)
+    _, port = be_server()
+    postgres.set_frige_config(connection, 901, {'temperature': 5, 'humidity': 20})
+
+    s = socket.socket(socket.AF_INET)
+    s.connect(("127.0.0.1", port))
+    s.send(_make_frame(9, 1.5, 40))
+    first = _read_target(s)
+    connection.execute(
+        "UPDATE DeviceConfig SET config = %s WHERE id = 901",
+        (json.dumps({'temperature': 3, 'humidity': 25}),))
+    time.sleep(0.5)
+    s.send(_make_frame(9, 1.5, 40))
+    changed = _read_target(s)
+
+    s.close()
+
+    assert first == (901, 20, 5)
+    assert changed == (901, 25, 3), "changed config is read again"
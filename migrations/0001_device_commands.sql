@@ -0,0 +1,12 @@
+-- Downlink commands, delivered to devices after their targets
+CREATE TABLE IF NOT EXISTS DeviceCommand (
+    id BIGSERIAL PRIMARY KEY,
+    device_id BIGINT NOT NULL,
+    command VARCHAR(64) NOT NULL,
+    args JSONB NOT NULL DEFAULT '{}',
+    status VARCHAR(16) NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS DeviceCommand_pending ON DeviceCommand (device_id) WHERE status = 'pending';
@@ -0,0 +1,13 @@
+-- Desired firmware of every device type, DeviceConfig.type refers to it
+CREATE TABLE IF NOT EXISTS Firmware (
+    type VARCHAR(255) PRIMARY KEY,
+    version BIGINT NOT NULL,
+    image BYTEA NOT NULL
+);
+
+-- Firmware version every device reported last
+CREATE TABLE IF NOT EXISTS DeviceFirmware (
+    device_id BIGINT PRIMARY KEY,
+    version BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
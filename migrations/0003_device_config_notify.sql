@@ -0,0 +1,26 @@
+-- Configs are written by other services, the table is created here for the trigger below
+CREATE TABLE IF NOT EXISTS DeviceConfig (
+    id BIGINT PRIMARY KEY,
+    type VARCHAR(255),
+    config JSONB,
+    ts NUMERIC
+);
+
+-- Every changed row is sent to the device_config channel with the device id
+-- as payload, servers drop the cached config of that device
+CREATE OR REPLACE FUNCTION notify_device_config() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        PERFORM pg_notify('device_config', OLD.id::text);
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        PERFORM pg_notify('device_config', NEW.id::text);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS DeviceConfig_notify ON DeviceConfig;
+CREATE TRIGGER DeviceConfig_notify
+    AFTER INSERT OR UPDATE OR DELETE ON DeviceConfig
+    FOR EACH ROW EXECUTE FUNCTION notify_device_config();
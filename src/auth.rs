@@ -0,0 +1,358 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use hmac::{Hmac, Mac};
+use log::error;
+use sha2::Sha256;
+
+use crate::device::HardDevice;
+use crate::protocol::{self, Frame, ProtocolError, FRAME_TYPE_AUTHENTICATED};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Authenticated frames wrap any other v2 frame for informers without TLS:
+// [informer_id: u32][counter: u64][frame type: u8][payload][HMAC-SHA256: 32]
+// The tag covers everything before it and is keyed with the informer's
+// pre-shared key. Counters have to grow with every frame, the last accepted one
+// of every informer is kept in a CounterStore.
+pub const AUTH_HEADER_SIZE: usize = 13;
+pub const TAG_SIZE: usize = 32;
+pub const MIN_KEY_SIZE: usize = 16;
+
+// Pre-shared keys by informer id, loaded from a file of `<informer id> <hex key>`
+// lines. Empty lines and lines starting with # are skipped.
+#[derive(Default)]
+pub struct KeyStore {
+    keys: HashMap<u32, Vec<u8>>,
+}
+
+impl KeyStore {
+    pub fn load(path: &str) -> io::Result<KeyStore> {
+        KeyStore::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> io::Result<KeyStore> {
+        let mut keys = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad_line = || io::Error::new(io::ErrorKind::InvalidData, format!("Bad key at line {}", number + 1));
+            let (informer_id, key) = line.split_once(char::is_whitespace).ok_or_else(bad_line)?;
+            let informer_id: u32 = informer_id.parse().map_err(|_| bad_line())?;
+            let key = hex::decode(key.trim()).map_err(|_| bad_line())?;
+            if key.len() < MIN_KEY_SIZE {
+                return Err(bad_line());
+            }
+            keys.insert(informer_id, key);
+        }
+        Ok(KeyStore { keys })
+    }
+
+    pub fn insert(&mut self, informer_id: u32, key: Vec<u8>) {
+        self.keys.insert(informer_id, key);
+    }
+
+    pub fn get(&self, informer_id: u32) -> Option<&[u8]> {
+        self.keys.get(&informer_id).map(Vec::as_slice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+// Last accepted frame counter of every informer. With a file the counters survive
+// restarts: a JSON object {"<informer id>": <counter>} written before a frame is
+// accepted, so frames sent before a restart can't be replayed after it.
+#[derive(Default)]
+pub struct CounterStore {
+    path: Option<PathBuf>,
+    counters: Mutex<HashMap<u32, u64>>,
+}
+
+impl CounterStore {
+    // A missing file is an empty store, it is created with the first counter
+    pub fn open(path: &str) -> io::Result<CounterStore> {
+        let counters = match fs::read_to_string(path) {
+            Ok(content) => CounterStore::parse(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(CounterStore {
+            path: Some(PathBuf::from(path)),
+            counters: Mutex::new(counters),
+        })
+    }
+
+    fn parse(content: &str) -> io::Result<HashMap<u32, u64>> {
+        let counters: HashMap<String, u64> =
+            serde_json::from_str(content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        counters
+            .into_iter()
+            .map(|(informer_id, counter)| match informer_id.parse() {
+                Ok(informer_id) => Ok((informer_id, counter)),
+                Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad informer id {}", informer_id))),
+            })
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.counters.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.lock().unwrap().is_empty()
+    }
+
+    // Takes the counter if it is newer than the last accepted one, a counter
+    // that can't be written is not taken either
+    pub fn advance(&self, informer_id: u32, counter: u64) -> Result<(), ProtocolError> {
+        let mut counters = self.counters.lock().unwrap();
+        let previous = match counters.get(&informer_id) {
+            Some(last) if *last >= counter => return Err(ProtocolError::StaleCounter { informer_id }),
+            last => last.copied(),
+        };
+        counters.insert(informer_id, counter);
+        if let Err(e) = self.write(&counters) {
+            error!("Can't save frame counters: {}", e);
+            match previous {
+                Some(previous) => counters.insert(informer_id, previous),
+                None => counters.remove(&informer_id),
+            };
+            return Err(ProtocolError::CounterNotSaved { informer_id });
+        }
+        Ok(())
+    }
+
+    // Through a temporary file so it is never half written
+    fn write(&self, counters: &HashMap<u32, u64>) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let counters: HashMap<String, u64> = counters.iter().map(|(id, counter)| (id.to_string(), *counter)).collect();
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_string(&counters)?).and_then(|_| fs::rename(&temporary, path))
+    }
+}
+
+// Stands in front of HardDevice::factory: informers with a key only get their
+// readings through in authenticated frames, others may keep sending plain ones.
+#[derive(Default)]
+pub struct FrameAuthenticator {
+    keys: KeyStore,
+    counters: CounterStore,
+}
+
+impl FrameAuthenticator {
+    pub fn new(keys: KeyStore, counters: CounterStore) -> FrameAuthenticator {
+        FrameAuthenticator { keys, counters }
+    }
+
+    pub fn factory(&self, buf: &[u8]) -> Result<Vec<HardDevice>, ProtocolError> {
+        let (frame, signer) = self.open(buf)?;
+        let devices = HardDevice::factory(&frame, frame.len())?;
+        self.check_devices(&devices, signer)?;
+        Ok(devices)
+    }
+
+    // Verifies and removes the authentication wrapper, the inner frame comes back
+    // with the informer that signed it. Other frames are returned as they are.
+    pub fn open<'a>(&self, buf: &'a [u8]) -> Result<(Cow<'a, [u8]>, Option<u32>), ProtocolError> {
+        let payload = match protocol::decode(buf)? {
+            Frame::V2 { frame_type: FRAME_TYPE_AUTHENTICATED, payload } => payload,
+            _ => return Ok((Cow::Borrowed(buf), None)),
+        };
+
+        if payload.len() < AUTH_HEADER_SIZE + TAG_SIZE {
+            return Err(ProtocolError::BadPayloadLength);
+        }
+        let informer_id = u32::from_le_bytes(payload[0..4].try_into().unwrap());
+        let counter = u64::from_le_bytes(payload[4..12].try_into().unwrap());
+        let (signed, tag) = payload.split_at(payload.len() - TAG_SIZE);
+        let key = self.keys.get(informer_id).ok_or(ProtocolError::Unauthenticated { informer_id })?;
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(signed);
+        mac.verify_slice(tag).map_err(|_| ProtocolError::BadTag { informer_id })?;
+        if payload[12] == FRAME_TYPE_AUTHENTICATED {
+            return Err(ProtocolError::UnsupportedFrameType(FRAME_TYPE_AUTHENTICATED));
+        }
+        self.counters.advance(informer_id, counter)?;
+        Ok((Cow::Owned(protocol::encode(payload[12], &signed[AUTH_HEADER_SIZE..])), Some(informer_id)))
+    }
+
+    // Informers with a key only get through in frames they signed themselves,
+    // others may keep sending plain ones
+    pub fn check_informer(&self, informer_id: u32, signer: Option<u32>) -> Result<(), ProtocolError> {
+        match signer {
+            Some(signer) if signer != informer_id => Err(ProtocolError::ForeignInformer { informer_id }),
+            None if self.keys.get(informer_id).is_some() => Err(ProtocolError::Unauthenticated { informer_id }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_devices(&self, devices: &[HardDevice], signer: Option<u32>) -> Result<(), ProtocolError> {
+        devices.iter().try_for_each(|d| self.check_informer(d.get_address().informer_id(), signer))
+    }
+}
+
+pub fn sign(key: &[u8], informer_id: u32, counter: u64, frame_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut signed = informer_id.to_le_bytes().to_vec();
+    signed.extend_from_slice(&counter.to_le_bytes());
+    signed.push(frame_type);
+    signed.extend_from_slice(payload);
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(&signed);
+    signed.extend_from_slice(&mac.finalize().into_bytes());
+    protocol::encode(FRAME_TYPE_AUTHENTICATED, &signed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{FRAME_TYPE_COMMAND_ACK, FRAME_TYPE_TELEMETRY, FRAME_TYPE_TELEMETRY_WIDE};
+
+    const KEY: [u8; 16] = [7; 16];
+
+    fn authenticator() -> FrameAuthenticator {
+        let mut keys = KeyStore::default();
+        keys.insert(12, KEY.to_vec());
+        FrameAuthenticator::new(keys, CounterStore::default())
+    }
+
+    fn telemetry(informer_id: u8) -> Vec<u8> {
+        let mut payload = vec![informer_id, 1];
+        payload.extend_from_slice(&1.5f32.to_le_bytes());
+        payload.extend_from_slice(&40f32.to_le_bytes());
+        payload
+    }
+
+    #[test]
+    fn test_key_file() {
+        let keys = KeyStore::parse("# informer key\n12 000102030405060708090a0b0c0d0e0f\n\n  13\tFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF\n").unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys.get(12), Some(&(0..16).collect::<Vec<u8>>()[..]));
+        assert_eq!(keys.get(13).map(|key| key.len()), Some(17));
+        assert_eq!(keys.get(14), None);
+
+        for content in ["12", "x 000102030405060708090a0b0c0d0e0f", "12 0001020304", "12 zz0102030405060708090a0b0c0d0e0f"] {
+            assert!(KeyStore::parse(content).is_err(), "{}", content);
+        }
+    }
+
+    #[test]
+    fn test_authenticated_frame() {
+        let authenticator = authenticator();
+        let frame = sign(&KEY, 12, 1, FRAME_TYPE_TELEMETRY, &telemetry(12));
+        let devices = authenticator.factory(&frame).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].get_id(), 1201);
+        assert_eq!(devices[0].get_temperature(), 1.5);
+
+        let mut wide = 12u32.to_le_bytes().to_vec();
+        wide.push(1);
+        wide.extend_from_slice(&300u16.to_le_bytes());
+        wide.extend_from_slice(&[0; 8]);
+        let frame = sign(&KEY, 12, 2, FRAME_TYPE_TELEMETRY_WIDE, &wide);
+        assert_eq!(authenticator.factory(&frame).unwrap()[0].get_address().informer_id(), 12);
+    }
+
+    #[test]
+    fn test_rejected_frames() {
+        let authenticator = authenticator();
+        let plain = protocol::encode(FRAME_TYPE_TELEMETRY, &telemetry(12));
+        assert_eq!(authenticator.factory(&plain).err(), Some(ProtocolError::Unauthenticated { informer_id: 12 }));
+        assert_eq!(authenticator.factory(&telemetry(12)).err(), Some(ProtocolError::Unauthenticated { informer_id: 12 }));
+        assert!(authenticator.factory(&telemetry(13)).is_ok(), "Informers without keys send plain frames");
+
+        let frame = sign(&[8; 16], 12, 1, FRAME_TYPE_TELEMETRY, &telemetry(12));
+        assert_eq!(authenticator.factory(&frame).err(), Some(ProtocolError::BadTag { informer_id: 12 }));
+        let frame = sign(&KEY, 13, 1, FRAME_TYPE_TELEMETRY, &telemetry(13));
+        assert_eq!(authenticator.factory(&frame).err(), Some(ProtocolError::Unauthenticated { informer_id: 13 }));
+        let frame = sign(&KEY, 12, 1, FRAME_TYPE_TELEMETRY, &telemetry(13));
+        assert_eq!(authenticator.factory(&frame).err(), Some(ProtocolError::ForeignInformer { informer_id: 13 }));
+
+        let mut tampered = sign(&KEY, 12, 1, FRAME_TYPE_TELEMETRY, &telemetry(12));
+        let payload_start = protocol::V2_HEADER_SIZE;
+        tampered[payload_start + AUTH_HEADER_SIZE + 2] ^= 1;
+        let tampered = protocol::encode(FRAME_TYPE_AUTHENTICATED, &tampered[payload_start..tampered.len() - protocol::CHECKSUM_SIZE]);
+        assert_eq!(authenticator.factory(&tampered).err(), Some(ProtocolError::BadTag { informer_id: 12 }));
+
+        let short = protocol::encode(FRAME_TYPE_AUTHENTICATED, &[0; AUTH_HEADER_SIZE + TAG_SIZE - 1]);
+        assert_eq!(authenticator.factory(&short).err(), Some(ProtocolError::BadPayloadLength));
+    }
+
+    #[test]
+    fn test_replay() {
+        let authenticator = authenticator();
+        let first = sign(&KEY, 12, 100, FRAME_TYPE_TELEMETRY, &telemetry(12));
+        assert!(authenticator.factory(&first).is_ok());
+        assert_eq!(authenticator.factory(&first).err(), Some(ProtocolError::StaleCounter { informer_id: 12 }));
+        let older = sign(&KEY, 12, 99, FRAME_TYPE_TELEMETRY, &telemetry(12));
+        assert_eq!(authenticator.factory(&older).err(), Some(ProtocolError::StaleCounter { informer_id: 12 }));
+        let bad = sign(&[8; 16], 12, 200, FRAME_TYPE_TELEMETRY, &telemetry(12));
+        assert!(authenticator.factory(&bad).is_err());
+        let next = sign(&KEY, 12, 101, FRAME_TYPE_TELEMETRY, &telemetry(12));
+        assert!(authenticator.factory(&next).is_ok(), "Rejected frames don't move the counter");
+    }
+
+    #[test]
+    fn test_replay_after_restart() {
+        let path = std::env::temp_dir().join(format!("be-server-counters-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+        let keys = || {
+            let mut keys = KeyStore::default();
+            keys.insert(12, KEY.to_vec());
+            keys
+        };
+
+        let authenticator = FrameAuthenticator::new(keys(), CounterStore::open(path).unwrap());
+        let frame = sign(&KEY, 12, 100, FRAME_TYPE_TELEMETRY, &telemetry(12));
+        assert!(authenticator.factory(&frame).is_ok());
+
+        let counters = CounterStore::open(path).unwrap();
+        assert_eq!(counters.len(), 1);
+        let authenticator = FrameAuthenticator::new(keys(), counters);
+        assert_eq!(authenticator.factory(&frame).err(), Some(ProtocolError::StaleCounter { informer_id: 12 }));
+        assert!(authenticator.factory(&sign(&KEY, 12, 101, FRAME_TYPE_TELEMETRY, &telemetry(12))).is_ok());
+
+        fs::write(path, "{\"x\": 1}").unwrap();
+        assert_eq!(CounterStore::open(path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_unsaved_counter() {
+        let path = std::env::temp_dir().join(format!("be-server-missing-{}", std::process::id())).join("counters.json");
+        let counters = CounterStore::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(counters.advance(12, 100), Err(ProtocolError::CounterNotSaved { informer_id: 12 }));
+        assert!(counters.is_empty(), "Counters that can't be saved are not taken");
+    }
+
+    #[test]
+    fn test_open() {
+        let authenticator = authenticator();
+        let plain = protocol::encode(FRAME_TYPE_COMMAND_ACK, &[0]);
+        assert_eq!(authenticator.open(&plain), Ok((Cow::Borrowed(&plain[..]), None)));
+        let signed = sign(&KEY, 12, 1, FRAME_TYPE_COMMAND_ACK, &[0]);
+        assert_eq!(authenticator.open(&signed), Ok((Cow::Owned(plain), Some(12))));
+        let nested = sign(&KEY, 12, 2, FRAME_TYPE_AUTHENTICATED, &signed[protocol::V2_HEADER_SIZE..signed.len() - protocol::CHECKSUM_SIZE]);
+        assert_eq!(authenticator.open(&nested), Err(ProtocolError::UnsupportedFrameType(FRAME_TYPE_AUTHENTICATED)));
+
+        assert_eq!(authenticator.check_informer(12, Some(12)), Ok(()));
+        assert_eq!(authenticator.check_informer(13, Some(12)), Err(ProtocolError::ForeignInformer { informer_id: 13 }));
+        assert_eq!(authenticator.check_informer(12, None), Err(ProtocolError::Unauthenticated { informer_id: 12 }));
+        assert_eq!(authenticator.check_informer(13, None), Ok(()));
+    }
+}
@@ -0,0 +1,226 @@
+use ciborium::value::Value;
+use std::error::Error;
+use std::fmt;
+
+// CBOR (RFC 8949) documents of constrained clients, read into the same JSON
+// values JSON documents are. The self-described CBOR marker may precede a
+// document, items JSON can't hold (byte strings, other tags, non-text keys) are refused.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CborError {
+    Malformed,
+    TooDeep,
+    TrailingBytes,
+    Unsupported,
+}
+
+impl fmt::Display for CborError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CborError::Malformed => write!(f, "Malformed CBOR item"),
+            CborError::TooDeep => write!(f, "CBOR items are nested too deep"),
+            CborError::TrailingBytes => write!(f, "Bytes after the CBOR item"),
+            CborError::Unsupported => write!(f, "CBOR item has no JSON counterpart"),
+        }
+    }
+}
+
+impl Error for CborError {}
+
+pub const SELF_DESCRIBED_TAG: u64 = 55799;
+const MAX_DEPTH: usize = 16;
+
+// Floats take the shortest size that keeps their value
+pub fn encode(value: &serde_json::Value) -> Vec<u8> {
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(value, &mut buf).expect("CBOR is written to memory");
+    buf
+}
+
+// The item has to take the whole buffer
+pub fn decode(buf: &[u8]) -> Result<serde_json::Value, CborError> {
+    let mut reader = buf;
+    let value: Value = ciborium::de::from_reader_with_recursion_limit(&mut reader, MAX_DEPTH).map_err(|e| match e {
+        ciborium::de::Error::RecursionLimitExceeded => CborError::TooDeep,
+        _ => CborError::Malformed,
+    })?;
+    if !reader.is_empty() {
+        return Err(CborError::TrailingBytes);
+    }
+    let value = match value {
+        Value::Tag(SELF_DESCRIBED_TAG, value) => *value,
+        value => value,
+    };
+    if !is_plain(&value) {
+        return Err(CborError::Unsupported);
+    }
+    value.deserialized().map_err(|_| CborError::Unsupported)
+}
+
+fn is_plain(value: &Value) -> bool {
+    match value {
+        Value::Bytes(_) | Value::Tag(..) => false,
+        Value::Float(value) => value.is_finite(),
+        Value::Array(items) => items.iter().all(is_plain),
+        Value::Map(entries) => entries.iter().all(|(key, value)| key.is_text() && is_plain(value)),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn hex(value: &str) -> Vec<u8> {
+        hex::decode(value).unwrap()
+    }
+
+    // RFC 8949 Appendix A, the examples JSON can hold
+    #[test]
+    fn test_decode_rfc_examples() {
+        let examples = [
+            ("00", json!(0)),
+            ("01", json!(1)),
+            ("0a", json!(10)),
+            ("17", json!(23)),
+            ("1818", json!(24)),
+            ("1819", json!(25)),
+            ("1864", json!(100)),
+            ("1903e8", json!(1000)),
+            ("1a000f4240", json!(1000000)),
+            ("1b000000e8d4a51000", json!(1000000000000u64)),
+            ("1bffffffffffffffff", json!(18446744073709551615u64)),
+            ("20", json!(-1)),
+            ("29", json!(-10)),
+            ("3863", json!(-100)),
+            ("3903e7", json!(-1000)),
+            ("f90000", json!(0.0)),
+            ("f98000", json!(-0.0)),
+            ("f93c00", json!(1.0)),
+            ("fb3ff199999999999a", json!(1.1)),
+            ("f93e00", json!(1.5)),
+            ("f97bff", json!(65504.0)),
+            ("fa47c35000", json!(100000.0)),
+            ("fa7f7fffff", json!(3.4028234663852886e+38)),
+            ("fb7e37e43c8800759c", json!(1.0e+300)),
+            ("f90001", json!(5.960464477539063e-8)),
+            ("f90400", json!(0.00006103515625)),
+            ("f9c400", json!(-4.0)),
+            ("fbc010666666666666", json!(-4.1)),
+            ("f4", json!(false)),
+            ("f5", json!(true)),
+            ("f6", json!(null)),
+            ("60", json!("")),
+            ("6161", json!("a")),
+            ("6449455446", json!("IETF")),
+            ("62225c", json!("\"\\")),
+            ("62c3bc", json!("\u{00fc}")),
+            ("63e6b0b4", json!("\u{6c34}")),
+            ("64f0908591", json!("\u{10151}")),
+            ("80", json!([])),
+            ("83010203", json!([1, 2, 3])),
+            ("8301820203820405", json!([1, [2, 3], [4, 5]])),
+            ("98190102030405060708090a0b0c0d0e0f101112131415161718181819", json!((1..=25).collect::<Vec<u32>>())),
+            ("a0", json!({})),
+            ("a26161016162820203", json!({"a": 1, "b": [2, 3]})),
+            ("826161a161626163", json!(["a", {"b": "c"}])),
+            ("a56161614161626142616361436164614461656145", json!({"a": "A", "b": "B", "c": "C", "d": "D", "e": "E"})),
+            ("7f657374726561646d696e67ff", json!("streaming")),
+            ("9fff", json!([])),
+            ("9f018202039f0405ffff", json!([1, [2, 3], [4, 5]])),
+            ("9f01820203820405ff", json!([1, [2, 3], [4, 5]])),
+            ("83018202039f0405ff", json!([1, [2, 3], [4, 5]])),
+            ("bf61610161629f0203ffff", json!({"a": 1, "b": [2, 3]})),
+            ("826161bf61626163ff", json!(["a", {"b": "c"}])),
+            ("bf6346756ef563416d7421ff", json!({"Fun": true, "Amt": -2})),
+        ];
+        for (encoded, value) in examples {
+            assert_eq!(decode(&hex(encoded)), Ok(value), "{}", encoded);
+        }
+    }
+
+    // The rest of Appendix A has no JSON counterpart
+    #[test]
+    fn test_decode_unsupported() {
+        let examples = [
+            "c249010000000000000000",
+            "3bffffffffffffffff",
+            "f97c00",
+            "f97e00",
+            "fbfff0000000000000",
+            "f0",
+            "f8ff",
+            "c074323031332d30332d32315432303a30343a30305a",
+            "c11a514b67b0",
+            "d74401020304",
+            "d818456449455446",
+            "4401020304",
+            "5f42010243030405ff",
+            "a201020304",
+        ];
+        for encoded in examples {
+            assert!(decode(&hex(encoded)).is_err(), "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        let examples = [
+            "",
+            "18",
+            "1901",
+            "6261",
+            "830102",
+            "a161",
+            "1c",
+            "ff",
+            "62c328",
+            "7bffffffffffffffff",
+            "9bffffffffffffffff",
+            "bbffffffffffffffff",
+            "5bffffffffffffffff00",
+            "9f01",
+            "7f6161",
+            "7f01ff",
+        ];
+        for encoded in examples {
+            assert_eq!(decode(&hex(encoded)), Err(CborError::Malformed), "{}", encoded);
+        }
+        assert_eq!(decode(&hex("0102")), Err(CborError::TrailingBytes));
+    }
+
+    #[test]
+    fn test_decode_nesting() {
+        let nested = |depth: usize| {
+            let mut buf = vec![0x81; depth];
+            buf.push(0x00);
+            buf
+        };
+        assert!(decode(&nested(MAX_DEPTH - 1)).is_ok());
+        assert_eq!(decode(&nested(MAX_DEPTH + 1)), Err(CborError::TooDeep));
+        assert_eq!(decode(&vec![0x9f; 100_000]), Err(CborError::TooDeep));
+        let mut maps = [0xa1, 0x61, 0x61].repeat(100_000);
+        maps.push(0x00);
+        assert_eq!(decode(&maps), Err(CborError::TooDeep));
+    }
+
+    #[test]
+    fn test_self_described() {
+        assert_eq!(decode(&hex("d9d9f7a1616101")), Ok(json!({"a": 1})));
+        assert_eq!(decode(&hex("d9d9f7d9d9f701")), Err(CborError::Unsupported), "Marker is expected once, at the start");
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(&json!(0)), hex("00"));
+        assert_eq!(encode(&json!(1000000)), hex("1a000f4240"));
+        assert_eq!(encode(&json!(-1000)), hex("3903e7"));
+        assert_eq!(encode(&json!(1.5)), hex("f93e00"));
+        assert_eq!(encode(&json!(100000.0)), hex("fa47c35000"));
+        assert_eq!(encode(&json!(1.1)), hex("fb3ff199999999999a"));
+        assert_eq!(encode(&json!(null)), hex("f6"));
+        assert_eq!(encode(&json!("IETF")), hex("6449455446"));
+        assert_eq!(encode(&json!([1, [2, 3], [4, 5]])), hex("8301820203820405"));
+        assert_eq!(encode(&json!({"a": 1, "b": [2, 3]})), hex("a26161016162820203"));
+    }
+}
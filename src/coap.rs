@@ -0,0 +1,350 @@
+use std::error::Error;
+use std::fmt;
+
+// Subset of CoAP (RFC 7252) needed to serve informers with off-the-shelf stacks:
+// [ver: 2 bits][type: 2 bits][token length: 4 bits][code: u8][message id: u16 BE][token]
+// then options, delta encoded against the previous option number, and
+// [0xFF][payload] when there is a payload. No blockwise transfer, no observe.
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 4;
+pub const MAX_TOKEN_LENGTH: usize = 8;
+const PAYLOAD_MARKER: u8 = 0xFF;
+
+pub const TYPE_CON: u8 = 0;
+pub const TYPE_NON: u8 = 1;
+pub const TYPE_ACK: u8 = 2;
+pub const TYPE_RST: u8 = 3;
+
+// Codes are class << 5 | detail, so 2.05 is 0x45
+pub const CODE_EMPTY: u8 = 0x00;
+pub const CODE_GET: u8 = 0x01;
+pub const CODE_POST: u8 = 0x02;
+pub const CODE_CHANGED: u8 = 0x44;
+pub const CODE_CONTENT: u8 = 0x45;
+pub const CODE_BAD_REQUEST: u8 = 0x80;
+pub const CODE_BAD_OPTION: u8 = 0x82;
+pub const CODE_FORBIDDEN: u8 = 0x83;
+pub const CODE_NOT_FOUND: u8 = 0x84;
+pub const CODE_METHOD_NOT_ALLOWED: u8 = 0x85;
+pub const CODE_NOT_ACCEPTABLE: u8 = 0x86;
+pub const CODE_REQUEST_ENTITY_TOO_LARGE: u8 = 0x8D;
+pub const CODE_UNSUPPORTED_CONTENT_FORMAT: u8 = 0x8F;
+
+pub const OPTION_URI_HOST: u16 = 3;
+pub const OPTION_URI_PORT: u16 = 7;
+pub const OPTION_URI_PATH: u16 = 11;
+pub const OPTION_CONTENT_FORMAT: u16 = 12;
+pub const OPTION_ACCEPT: u16 = 17;
+pub const OPTION_SIZE1: u16 = 60;
+
+pub const CONTENT_FORMAT_OCTET_STREAM: u16 = 42;
+pub const CONTENT_FORMAT_CBOR: u16 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoapError {
+    Truncated,
+    UnsupportedVersion(u8),
+    BadTokenLength(u8),
+    BadOption,
+}
+
+impl fmt::Display for CoapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoapError::Truncated => write!(f, "Not enough data to parse CoAP message"),
+            CoapError::UnsupportedVersion(version) => write!(f, "Unsupported CoAP version {}", version),
+            CoapError::BadTokenLength(length) => write!(f, "Bad CoAP token length {}", length),
+            CoapError::BadOption => write!(f, "Malformed CoAP option"),
+        }
+    }
+}
+
+impl Error for CoapError {}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Message {
+    pub message_type: u8,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    // Kept in the order they are added, encode sorts them by number
+    pub options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn decode(buf: &[u8]) -> Result<Message, CoapError> {
+        if buf.len() < HEADER_SIZE {
+            return Err(CoapError::Truncated);
+        }
+        let version = buf[0] >> 6;
+        if version != VERSION {
+            return Err(CoapError::UnsupportedVersion(version));
+        }
+        let token_length = buf[0] & 0x0F;
+        if token_length as usize > MAX_TOKEN_LENGTH {
+            return Err(CoapError::BadTokenLength(token_length));
+        }
+        let mut offset = HEADER_SIZE + token_length as usize;
+        if buf.len() < offset {
+            return Err(CoapError::Truncated);
+        }
+        let mut message = Message {
+            message_type: (buf[0] >> 4) & 0x03,
+            code: buf[1],
+            message_id: u16::from_be_bytes([buf[2], buf[3]]),
+            token: buf[HEADER_SIZE..offset].to_vec(),
+            ..Default::default()
+        };
+
+        let mut option_number = 0u16;
+        while offset < buf.len() {
+            if buf[offset] == PAYLOAD_MARKER {
+                // Marker followed by nothing is a format error
+                if offset + 1 == buf.len() {
+                    return Err(CoapError::BadOption);
+                }
+                message.payload = buf[offset + 1..].to_vec();
+                break;
+            }
+            let header = buf[offset];
+            offset += 1;
+            let delta = read_option_nibble(buf, &mut offset, header >> 4)?;
+            let length = read_option_nibble(buf, &mut offset, header & 0x0F)? as usize;
+            option_number = option_number.checked_add(delta).ok_or(CoapError::BadOption)?;
+            if buf.len() < offset + length {
+                return Err(CoapError::Truncated);
+            }
+            message.options.push((option_number, buf[offset..offset + length].to_vec()));
+            offset += length;
+        }
+        Ok(message)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![
+            VERSION << 6 | (self.message_type & 0x03) << 4 | self.token.len() as u8,
+            self.code,
+        ];
+        buf.extend_from_slice(&self.message_id.to_be_bytes());
+        buf.extend_from_slice(&self.token);
+
+        let mut options: Vec<&(u16, Vec<u8>)> = self.options.iter().collect();
+        options.sort_by_key(|(number, _)| *number);
+        let mut option_number = 0u16;
+        for (number, value) in options {
+            let (delta, delta_ext) = option_nibble(number - option_number);
+            let (length, length_ext) = option_nibble(value.len() as u16);
+            buf.push(delta << 4 | length);
+            buf.extend_from_slice(&delta_ext);
+            buf.extend_from_slice(&length_ext);
+            buf.extend_from_slice(value);
+            option_number = *number;
+        }
+
+        if !self.payload.is_empty() {
+            buf.push(PAYLOAD_MARKER);
+            buf.extend_from_slice(&self.payload);
+        }
+        buf
+    }
+
+    // Confirmable requests get a piggybacked ACK, non-confirmable ones a NON
+    // response with the message id picked by the server
+    pub fn response(&self, code: u8, message_id: u16) -> Message {
+        let (message_type, message_id) = match self.message_type {
+            TYPE_CON => (TYPE_ACK, self.message_id),
+            _ => (TYPE_NON, message_id),
+        };
+        Message {
+            message_type,
+            code,
+            message_id,
+            token: self.token.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_request(&self) -> bool {
+        (self.message_type == TYPE_CON || self.message_type == TYPE_NON) && self.code >> 5 == 0 && self.code != CODE_EMPTY
+    }
+
+    pub fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options.iter().find(|(n, _)| *n == number).map(|(_, value)| value.as_slice())
+    }
+
+    pub fn add_option(&mut self, number: u16, value: Vec<u8>) {
+        self.options.push((number, value));
+    }
+
+    pub fn uri_path(&self) -> Vec<String> {
+        self.options
+            .iter()
+            .filter(|(number, _)| *number == OPTION_URI_PATH)
+            .map(|(_, segment)| String::from_utf8_lossy(segment).into_owned())
+            .collect()
+    }
+
+    pub fn content_format(&self) -> Option<u16> {
+        self.option(OPTION_CONTENT_FORMAT).map(decode_uint)
+    }
+
+    pub fn accept(&self) -> Option<u16> {
+        self.option(OPTION_ACCEPT).map(decode_uint)
+    }
+
+    pub fn set_content_format(&mut self, content_format: u16) {
+        self.add_option(OPTION_CONTENT_FORMAT, encode_uint(content_format));
+    }
+
+    // Odd option numbers are critical, a request with an unknown one must be rejected
+    pub fn unknown_critical_option(&self, known: &[u16]) -> Option<u16> {
+        self.options
+            .iter()
+            .map(|(number, _)| *number)
+            .find(|number| number % 2 == 1 && !known.contains(number))
+    }
+}
+
+// uint options are big-endian without leading zero bytes, zero is empty
+pub fn encode_uint(value: u16) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+pub fn decode_uint(value: &[u8]) -> u16 {
+    value.iter().fold(0u16, |acc, b| acc.wrapping_shl(8) | *b as u16)
+}
+
+fn read_option_nibble(buf: &[u8], offset: &mut usize, nibble: u8) -> Result<u16, CoapError> {
+    match nibble {
+        0..=12 => Ok(nibble as u16),
+        13 => {
+            let value = *buf.get(*offset).ok_or(CoapError::Truncated)?;
+            *offset += 1;
+            Ok(value as u16 + 13)
+        }
+        14 => {
+            let value = buf.get(*offset..*offset + 2).ok_or(CoapError::Truncated)?;
+            *offset += 2;
+            u16::from_be_bytes([value[0], value[1]]).checked_add(269).ok_or(CoapError::BadOption)
+        }
+        _ => Err(CoapError::BadOption),
+    }
+}
+
+fn option_nibble(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, Vec::new()),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_get() {
+        // CON GET /c/1201, message id 0x1234, token 0xAB, Accept: 60
+        let buf = [0x41, 0x01, 0x12, 0x34, 0xAB, 0xB1, b'c', 0x04, b'1', b'2', b'0', b'1', 0x61, 60];
+        let message = Message::decode(&buf).unwrap();
+        assert_eq!(message.message_type, TYPE_CON);
+        assert_eq!(message.code, CODE_GET);
+        assert_eq!(message.message_id, 0x1234);
+        assert_eq!(message.token, vec![0xAB]);
+        assert_eq!(message.uri_path(), vec!["c", "1201"]);
+        assert_eq!(message.accept(), Some(CONTENT_FORMAT_CBOR));
+        assert_eq!(message.content_format(), None);
+        assert!(message.payload.is_empty());
+        assert!(message.is_request());
+        assert_eq!(message.encode(), buf);
+    }
+
+    #[test]
+    fn test_roundtrip_with_payload() {
+        let mut message = Message {
+            message_type: TYPE_NON,
+            code: CODE_POST,
+            message_id: 7,
+            token: vec![1, 2, 3, 4],
+            payload: vec![12, 0],
+            ..Default::default()
+        };
+        message.set_content_format(CONTENT_FORMAT_OCTET_STREAM);
+        message.add_option(OPTION_URI_PATH, b"t".to_vec());
+        message.add_option(OPTION_URI_PATH, b"12".to_vec());
+        let decoded = Message::decode(&message.encode()).unwrap();
+        assert_eq!(decoded.uri_path(), vec!["t", "12"]);
+        assert_eq!(decoded.content_format(), Some(CONTENT_FORMAT_OCTET_STREAM));
+        assert_eq!(decoded.payload, vec![12, 0]);
+        assert_eq!(decoded.token, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_extended_option_fields() {
+        let mut message = Message {
+            message_type: TYPE_CON,
+            code: CODE_GET,
+            ..Default::default()
+        };
+        message.add_option(OPTION_URI_PATH, vec![b'x'; 300]);
+        message.add_option(1000, vec![1]);
+        message.add_option(20, vec![b'y'; 20]);
+        let decoded = Message::decode(&message.encode()).unwrap();
+        assert_eq!(decoded.option(OPTION_URI_PATH).unwrap().len(), 300);
+        assert_eq!(decoded.option(20).unwrap().len(), 20);
+        assert_eq!(decoded.option(1000), Some(&[1u8][..]));
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        assert_eq!(Message::decode(&[0x40, 0x01, 0]), Err(CoapError::Truncated));
+        assert_eq!(Message::decode(&[0x80, 0x01, 0, 0]), Err(CoapError::UnsupportedVersion(2)));
+        assert_eq!(Message::decode(&[0x49, 0x01, 0, 0]), Err(CoapError::BadTokenLength(9)));
+        assert_eq!(Message::decode(&[0x42, 0x01, 0, 0, 1]), Err(CoapError::Truncated));
+        assert_eq!(Message::decode(&[0x40, 0x01, 0, 0, 0xFF]), Err(CoapError::BadOption));
+        assert_eq!(Message::decode(&[0x40, 0x01, 0, 0, 0xF1, 0]), Err(CoapError::BadOption));
+        assert_eq!(Message::decode(&[0x40, 0x01, 0, 0, 0xB4, b'c']), Err(CoapError::Truncated));
+    }
+
+    #[test]
+    fn test_responses() {
+        let request = Message {
+            message_type: TYPE_CON,
+            code: CODE_GET,
+            message_id: 10,
+            token: vec![9],
+            ..Default::default()
+        };
+        let response = request.response(CODE_CONTENT, 99);
+        assert_eq!((response.message_type, response.message_id), (TYPE_ACK, 10));
+        assert_eq!(response.token, vec![9]);
+
+        let request = Message { message_type: TYPE_NON, ..request };
+        let response = request.response(CODE_CONTENT, 99);
+        assert_eq!((response.message_type, response.message_id), (TYPE_NON, 99));
+    }
+
+    #[test]
+    fn test_critical_options() {
+        let mut message = Message::default();
+        message.add_option(OPTION_URI_PATH, b"c".to_vec());
+        message.add_option(OPTION_CONTENT_FORMAT, vec![]);
+        message.add_option(2048, vec![]);
+        assert_eq!(message.unknown_critical_option(&[OPTION_URI_PATH]), None, "Elective options are ignored");
+        message.add_option(OPTION_ACCEPT, vec![]);
+        assert_eq!(message.unknown_critical_option(&[OPTION_URI_PATH]), Some(OPTION_ACCEPT));
+    }
+
+    #[test]
+    fn test_uint_options() {
+        assert_eq!(encode_uint(0), Vec::<u8>::new());
+        assert_eq!(encode_uint(60), vec![60]);
+        assert_eq!(encode_uint(0x0102), vec![1, 2]);
+        assert_eq!(decode_uint(&[]), 0);
+        assert_eq!(decode_uint(&[1, 2]), 0x0102);
+    }
+}
@@ -0,0 +1,388 @@
+use async_std::io as aio;
+use async_std::task;
+use be_server::cbor;
+use be_server::coap::{self, Message};
+use be_server::device::{Device, HardDevice};
+use be_server::external::abstract_external::ExternalDatabase;
+use be_server::setpoint::Setpoint;
+use hashlink::LruCache;
+use log::error;
+use serde_json::{json, Value};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use crate::session;
+use crate::state::GlobalState;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+// Fits legacy frames of 255 devices (2042 bytes) and their signed wrapper
+const MAX_PAYLOAD_SIZE: usize = 4096;
+// EXCHANGE_LIFETIME of RFC 7252 4.8.2 with the default transmission parameters
+const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+const MAX_EXCHANGES: usize = 4096;
+const TELEMETRY_RESOURCE: &str = "t";
+const CONFIG_RESOURCE: &str = "c";
+const KNOWN_OPTIONS: [u16; 4] = [
+    coap::OPTION_URI_HOST,
+    coap::OPTION_URI_PORT,
+    coap::OPTION_URI_PATH,
+    coap::OPTION_ACCEPT,
+];
+
+enum Exchange {
+    New,
+    InProgress,
+    Answered(Vec<u8>),
+}
+
+// Message ids seen per peer within the exchange lifetime (RFC 7252 4.5).
+// Retransmitted confirmable requests get the response sent before, the
+// oldest exchanges are forgotten first when there are too many.
+struct Exchanges {
+    recent: Mutex<LruCache<(SocketAddr, u16), (Instant, Option<Vec<u8>>)>>,
+}
+
+impl Exchanges {
+    fn new() -> Exchanges {
+        Exchanges { recent: Mutex::new(LruCache::new(MAX_EXCHANGES)) }
+    }
+
+    fn begin(&self, peer: SocketAddr, message_id: u16) -> Exchange {
+        let mut recent = self.recent.lock().unwrap();
+        match recent.get(&(peer, message_id)) {
+            Some((received, _)) if received.elapsed() >= EXCHANGE_LIFETIME => {}
+            Some((_, Some(response))) => return Exchange::Answered(response.clone()),
+            Some((_, None)) => return Exchange::InProgress,
+            None => {}
+        }
+        recent.insert((peer, message_id), (Instant::now(), None));
+        Exchange::New
+    }
+
+    fn complete(&self, peer: SocketAddr, message_id: u16, response: &[u8]) {
+        if let Some((_, cached)) = self.recent.lock().unwrap().peek_mut(&(peer, message_id)) {
+            *cached = Some(response.to_vec());
+        }
+    }
+}
+
+// CoAP front for informers with stock firmware stacks:
+// POST /t/{informer} carries a telemetry frame of that informer,
+// GET /c/{device} returns its targets as target_as_bytes or a CBOR map.
+// Duplicates of a request are processed once.
+pub async fn coap_listener_routine(
+    process_running: Arc<AtomicBool>,
+    addr: String,
+    state: GlobalState,
+    service_counter: Arc<AtomicUsize>,
+    external_database: Arc<dyn ExternalDatabase>,
+) -> io::Result<()> {
+    println!("Start CoAP listen on: {}", addr);
+    let socket = Arc::new(UdpSocket::bind(addr).await?);
+    println!("CoAP Listener started");
+    service_counter.fetch_sub(1, Ordering::Relaxed);
+    let message_id = Arc::new(AtomicU16::new(chrono::Utc::now().timestamp_millis() as u16));
+    let exchanges = Arc::new(Exchanges::new());
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        if !process_running.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let received = aio::timeout(Duration::from_secs(1), async {
+            socket.recv_from(&mut buf).await
+        })
+        .await;
+        let (n, peer) = match received {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let request = match Message::decode(&buf[..n]) {
+            Ok(request) => request,
+            Err(e) => {
+                error!("CoAP message from {} rejected: {}", peer, e);
+                continue;
+            }
+        };
+        if !request.is_request() {
+            continue;
+        }
+        match exchanges.begin(peer, request.message_id) {
+            Exchange::New => {}
+            Exchange::Answered(response) if request.message_type == coap::TYPE_CON => {
+                if let Err(e) = socket.send_to(&response, peer).await {
+                    error!("Error: {}", e);
+                }
+                continue;
+            }
+            Exchange::Answered(_) | Exchange::InProgress => continue,
+        }
+
+        let request_state = state.clone();
+        let request_database = external_database.clone();
+        let request_socket = socket.clone();
+        let request_message_id = message_id.clone();
+        let request_exchanges = exchanges.clone();
+        task::spawn(async move {
+            let response_id = request_message_id.fetch_add(1, Ordering::Relaxed);
+            let (response, targets) = handle_request(&request, peer, &request_state, request_database.as_ref(), response_id).await;
+            let response = response.encode();
+            request_exchanges.complete(peer, request.message_id, &response);
+            match request_socket.send_to(&response, peer).await {
+                Ok(_) => request_state.record_targets(&targets),
+                Err(e) => error!("Error: {}", e),
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    request: &Message,
+    peer: SocketAddr,
+    state: &GlobalState,
+    external_database: &dyn ExternalDatabase,
+    response_id: u16,
+) -> (Message, Vec<(u64, Setpoint)>) {
+    if let Some(option) = request.unknown_critical_option(&KNOWN_OPTIONS) {
+        error!("CoAP request from {} has unsupported option {}", peer, option);
+        return (request.response(coap::CODE_BAD_OPTION, response_id), Vec::new());
+    }
+    if request.payload.len() > MAX_PAYLOAD_SIZE {
+        error!("CoAP request from {} has {} bytes of payload", peer, request.payload.len());
+        let mut response = request.response(coap::CODE_REQUEST_ENTITY_TOO_LARGE, response_id);
+        response.add_option(coap::OPTION_SIZE1, coap::encode_uint(MAX_PAYLOAD_SIZE as u16));
+        return (response, Vec::new());
+    }
+    let path = request.uri_path();
+    let path: Vec<&str> = path.iter().map(String::as_str).collect();
+    match (request.code, path.as_slice()) {
+        (coap::CODE_POST, [TELEMETRY_RESOURCE, informer_id]) => {
+            (post_telemetry(request, informer_id, state, response_id), Vec::new())
+        }
+        (coap::CODE_GET, [CONFIG_RESOURCE, device_id]) => {
+            get_config(request, device_id, state, external_database, response_id).await
+        }
+        (_, [TELEMETRY_RESOURCE, _]) | (_, [CONFIG_RESOURCE, _]) => {
+            (request.response(coap::CODE_METHOD_NOT_ALLOWED, response_id), Vec::new())
+        }
+        _ => (request.response(coap::CODE_NOT_FOUND, response_id), Vec::new()),
+    }
+}
+
+fn post_telemetry(request: &Message, informer_id: &str, state: &GlobalState, response_id: u16) -> Message {
+    match request.content_format() {
+        None | Some(coap::CONTENT_FORMAT_OCTET_STREAM) => {}
+        Some(_) => return request.response(coap::CODE_UNSUPPORTED_CONTENT_FORMAT, response_id),
+    }
+    let informer_id: u32 = match informer_id.parse() {
+        Ok(informer_id) => informer_id,
+        Err(_) => return request.response(coap::CODE_BAD_REQUEST, response_id),
+    };
+    let devices = match state.authenticate_frame(&request.payload) {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!("Frame rejected: {}", e);
+            let mut response = request.response(coap::CODE_BAD_REQUEST, response_id);
+            response.payload = e.to_string().into_bytes();
+            return response;
+        }
+    };
+    // The path is what the CoAP stack was provisioned with, frames of other informers are not trusted
+    if let Some(device) = devices.iter().find(|d| d.get_address().informer_id() != informer_id) {
+        error!("Informer {} reported device {} of another informer", informer_id, device.get_id());
+        return request.response(coap::CODE_FORBIDDEN, response_id);
+    }
+    if session::is_fresh(&devices, state) {
+        for hdevice in devices {
+            println!("{}", hdevice.as_json());
+            state.new_device(hdevice);
+        }
+    }
+    request.response(coap::CODE_CHANGED, response_id)
+}
+
+// The config target is returned to be recorded once the response is sent
+async fn get_config(
+    request: &Message,
+    device_id: &str,
+    state: &GlobalState,
+    external_database: &dyn ExternalDatabase,
+    response_id: u16,
+) -> (Message, Vec<(u64, Setpoint)>) {
+    let content_format = match request.accept() {
+        None => coap::CONTENT_FORMAT_OCTET_STREAM,
+        Some(accept @ (coap::CONTENT_FORMAT_OCTET_STREAM | coap::CONTENT_FORMAT_CBOR)) => accept,
+        Some(_) => return (request.response(coap::CODE_NOT_ACCEPTABLE, response_id), Vec::new()),
+    };
+    let mut hdevice = match device_id.parse().ok().and_then(HardDevice::from_device_id) {
+        Some(hdevice) => hdevice,
+        None => return (request.response(coap::CODE_BAD_REQUEST, response_id), Vec::new()),
+    };
+    let target = match session::configure(&mut hdevice, state, external_database).await {
+        Ok(target) => target,
+        Err(e) => {
+            println!("No config for device {}: {}", device_id, e);
+            return (request.response(coap::CODE_NOT_FOUND, response_id), Vec::new());
+        }
+    };
+
+    let payload = match content_format {
+        coap::CONTENT_FORMAT_CBOR => cbor::encode(&target_as_cbor(&hdevice)),
+        _ => match hdevice.target_as_bytes() {
+            Ok(targets) => targets,
+            Err(e) => {
+                error!("{}", e);
+                return (request.response(coap::CODE_NOT_FOUND, response_id), Vec::new());
+            }
+        },
+    };
+    let mut response = request.response(coap::CODE_CONTENT, response_id);
+    response.set_content_format(content_format);
+    response.payload = payload;
+    (response, target.map(|target| (hdevice.get_id(), target)).into_iter().collect())
+}
+
+fn target_as_cbor(hdevice: &HardDevice) -> Value {
+    json!({
+        "id": hdevice.get_id(),
+        "temperature": hdevice.get_target_temperature().unwrap_or_default(),
+        "humidity": hdevice.get_target_humidity().unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use async_std::task::block_on;
+    use async_trait::async_trait;
+    use clap::Parser;
+    use std::sync::mpsc::channel;
+    use std::{net, thread};
+
+    struct MockExternalDatabase {}
+
+    #[async_trait]
+    impl ExternalDatabase for MockExternalDatabase {
+        async fn get_device_config(&self, device_id: u64) -> Result<String, std::io::Error> {
+            match device_id {
+                1201 => Ok("{\"temperature\": 4.5, \"humidity\": 55.0}".to_owned()),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "No config")),
+            }
+        }
+    }
+
+    fn request(code: u8, message_id: u16, path: &[&str]) -> Message {
+        let mut request = Message {
+            message_type: coap::TYPE_CON,
+            code,
+            message_id,
+            token: vec![0xA0, message_id as u8],
+            ..Default::default()
+        };
+        path.iter().for_each(|segment| request.add_option(coap::OPTION_URI_PATH, segment.as_bytes().to_vec()));
+        request
+    }
+
+    fn exchange(client: &net::UdpSocket, request: &Message) -> Message {
+        client.send_to(&request.encode(), "127.0.0.1:32146").unwrap();
+        let mut buf = [0; 256];
+        let (n, _) = client.recv_from(&mut buf).unwrap();
+        let response = Message::decode(&buf[..n]).unwrap();
+        assert_eq!(response.message_type, coap::TYPE_ACK);
+        assert_eq!(response.message_id, request.message_id);
+        assert_eq!(response.token, request.token);
+        response
+    }
+
+    #[test]
+    fn test_loopback() {
+        let (snd, rcv) = channel::<HardDevice>();
+        let state = GlobalState::new(snd, ServerConfig::parse_from(["be-server", "--cport", "32146"]));
+        let process_running = Arc::new(AtomicBool::new(true));
+        let service_counter = Arc::new(AtomicUsize::new(1));
+
+        let listener_running = process_running.clone();
+        let listener_counter = service_counter.clone();
+        let listener_addr = state.get_coap_addr().unwrap();
+        let listener = thread::spawn(move || {
+            block_on(coap_listener_routine(
+                listener_running,
+                listener_addr,
+                state,
+                listener_counter,
+                Arc::new(MockExternalDatabase {}),
+            ))
+        });
+        while service_counter.load(Ordering::Relaxed) != 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut frame = vec![12, 1];
+        frame.extend_from_slice(&0.32f32.to_le_bytes());
+        frame.extend_from_slice(&0.98f32.to_le_bytes());
+        let mut telemetry = request(coap::CODE_POST, 1, &["t", "12"]);
+        telemetry.payload = frame.clone();
+        assert_eq!(exchange(&client, &telemetry).code, coap::CODE_CHANGED);
+        let hdevice = rcv.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(hdevice.get_id(), 1201);
+        assert_eq!(hdevice.get_temperature(), 0.32);
+
+        let mut telemetry = request(coap::CODE_POST, 2, &["t", "13"]);
+        telemetry.payload = frame;
+        assert_eq!(exchange(&client, &telemetry).code, coap::CODE_FORBIDDEN, "Frame of another informer");
+        let mut telemetry = request(coap::CODE_POST, 3, &["t", "12"]);
+        telemetry.payload = vec![12, 1, 0];
+        let response = exchange(&client, &telemetry);
+        assert_eq!(response.code, coap::CODE_BAD_REQUEST);
+        assert_eq!(response.payload, b"Not enough data to parse devices");
+        assert!(rcv.try_recv().is_err(), "Rejected frames are not published");
+
+        let response = exchange(&client, &request(coap::CODE_GET, 4, &["c", "1201"]));
+        assert_eq!(response.code, coap::CODE_CONTENT);
+        assert_eq!(response.content_format(), Some(coap::CONTENT_FORMAT_OCTET_STREAM));
+        let mut target = 1201u32.to_le_bytes().to_vec();
+        target.extend_from_slice(&55.0f32.to_le_bytes());
+        target.extend_from_slice(&4.5f32.to_le_bytes());
+        assert_eq!(response.payload, target);
+
+        let mut config = request(coap::CODE_GET, 5, &["c", "1201"]);
+        config.add_option(coap::OPTION_ACCEPT, coap::encode_uint(coap::CONTENT_FORMAT_CBOR));
+        let response = exchange(&client, &config);
+        assert_eq!(response.content_format(), Some(coap::CONTENT_FORMAT_CBOR));
+        let expected = json!({"id": 1201, "temperature": 4.5, "humidity": 55.0});
+        assert_eq!(cbor::decode(&response.payload), Ok(expected));
+
+        // Retransmission of a processed request gets the same response
+        let mut telemetry = request(coap::CODE_POST, 9, &["t", "12"]);
+        telemetry.payload = vec![12, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+        let response = exchange(&client, &telemetry);
+        assert_eq!(exchange(&client, &telemetry), response);
+        rcv.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(rcv.recv_timeout(Duration::from_millis(200)).is_err(), "Duplicate is not published again");
+
+        let mut telemetry = request(coap::CODE_POST, 10, &["t", "12"]);
+        telemetry.payload = vec![12, 255];
+        telemetry.payload.extend(vec![0; 255 * 8]);
+        assert_eq!(telemetry.payload.len(), 2042);
+        assert_eq!(exchange(&client, &telemetry).code, coap::CODE_CHANGED, "Largest legacy frame");
+        telemetry.message_id = 11;
+        telemetry.payload = vec![0; MAX_PAYLOAD_SIZE + 1];
+        let response = exchange(&client, &telemetry);
+        assert_eq!(response.code, coap::CODE_REQUEST_ENTITY_TOO_LARGE);
+        assert_eq!(response.option(coap::OPTION_SIZE1), Some(&coap::encode_uint(MAX_PAYLOAD_SIZE as u16)[..]));
+
+        assert_eq!(exchange(&client, &request(coap::CODE_GET, 6, &["c", "1202"])).code, coap::CODE_NOT_FOUND);
+        assert_eq!(exchange(&client, &request(coap::CODE_POST, 7, &["c", "1201"])).code, coap::CODE_METHOD_NOT_ALLOWED);
+        assert_eq!(exchange(&client, &request(coap::CODE_GET, 8, &["x"])).code, coap::CODE_NOT_FOUND);
+
+        process_running.store(false, Ordering::Relaxed);
+        listener.join().unwrap().unwrap();
+    }
+}
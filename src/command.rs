@@ -0,0 +1,174 @@
+use serde_json::{json, Value};
+use std::fmt;
+
+use crate::protocol::ProtocolError;
+
+// Commands queued for a device besides its targets. They are kept in the
+// DeviceCommand table as a name and JSON arguments, e.g.
+// ('set_reporting_interval', {"seconds": 60}), and go to v2 informers as
+// [device_id: u64][command_id: u64][code: u8][arguments] COMMAND frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandKind {
+    Reboot,
+    ForceDefrost,
+    SetReportingInterval { seconds: u32 },
+    Identify { seconds: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandStatus {
+    Pending,
+    Delivered,
+    Acknowledged,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    pub id: u64,
+    pub device_id: u64,
+    pub kind: CommandKind,
+}
+
+impl CommandKind {
+    pub fn from_json(name: &str, args: &Value) -> Option<CommandKind> {
+        let seconds = args["seconds"].as_u64();
+        match name {
+            "reboot" => Some(CommandKind::Reboot),
+            "force_defrost" => Some(CommandKind::ForceDefrost),
+            "set_reporting_interval" => Some(CommandKind::SetReportingInterval {
+                seconds: u32::try_from(seconds?).ok().filter(|seconds| *seconds > 0)?,
+            }),
+            "identify" => Some(CommandKind::Identify {
+                seconds: u16::try_from(seconds?).ok()?,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandKind::Reboot => "reboot",
+            CommandKind::ForceDefrost => "force_defrost",
+            CommandKind::SetReportingInterval { .. } => "set_reporting_interval",
+            CommandKind::Identify { .. } => "identify",
+        }
+    }
+
+    pub fn args(&self) -> Value {
+        match self {
+            CommandKind::Reboot | CommandKind::ForceDefrost => json!({}),
+            CommandKind::SetReportingInterval { seconds } => json!({ "seconds": seconds }),
+            CommandKind::Identify { seconds } => json!({ "seconds": seconds }),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            CommandKind::Reboot => 0x01,
+            CommandKind::ForceDefrost => 0x02,
+            CommandKind::SetReportingInterval { .. } => 0x03,
+            CommandKind::Identify { .. } => 0x04,
+        }
+    }
+
+    // Arguments are little-endian like everything else on the wire
+    pub fn encode_args(&self) -> Vec<u8> {
+        match self {
+            CommandKind::Reboot | CommandKind::ForceDefrost => Vec::new(),
+            CommandKind::SetReportingInterval { seconds } => seconds.to_le_bytes().to_vec(),
+            CommandKind::Identify { seconds } => seconds.to_le_bytes().to_vec(),
+        }
+    }
+}
+
+impl CommandStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandStatus::Pending => "pending",
+            CommandStatus::Delivered => "delivered",
+            CommandStatus::Acknowledged => "acknowledged",
+        }
+    }
+}
+
+impl fmt::Display for CommandStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Command {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = self.device_id.to_le_bytes().to_vec();
+        payload.extend_from_slice(&self.id.to_le_bytes());
+        payload.push(self.kind.code());
+        payload.extend(self.kind.encode_args());
+        payload
+    }
+}
+
+// COMMAND_ACK payload from informers: [count: u8][(command_id: u64) * count]
+pub fn parse_acknowledgement(payload: &[u8]) -> Result<Vec<u64>, ProtocolError> {
+    let count = *payload.first().ok_or(ProtocolError::BadPayloadLength)? as usize;
+    if payload.len() != 1 + count * 8 {
+        return Err(ProtocolError::BadPayloadLength);
+    }
+    Ok(payload[1..].chunks(8).map(|id| u64::from_le_bytes(id.try_into().unwrap())).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_json() {
+        assert_eq!(CommandKind::from_json("reboot", &json!({})), Some(CommandKind::Reboot));
+        assert_eq!(CommandKind::from_json("force_defrost", &Value::Null), Some(CommandKind::ForceDefrost));
+        assert_eq!(
+            CommandKind::from_json("set_reporting_interval", &json!({"seconds": 60})),
+            Some(CommandKind::SetReportingInterval { seconds: 60 })
+        );
+        assert_eq!(CommandKind::from_json("identify", &json!({"seconds": 5})), Some(CommandKind::Identify { seconds: 5 }));
+        assert_eq!(CommandKind::from_json("set_reporting_interval", &json!({"seconds": 0})), None);
+        assert_eq!(CommandKind::from_json("set_reporting_interval", &json!({})), None);
+        assert_eq!(CommandKind::from_json("identify", &json!({"seconds": 70000})), None);
+        assert_eq!(CommandKind::from_json("self_destruct", &json!({})), None);
+
+        for kind in [
+            CommandKind::Reboot,
+            CommandKind::ForceDefrost,
+            CommandKind::SetReportingInterval { seconds: 900 },
+            CommandKind::Identify { seconds: 10 },
+        ] {
+            assert_eq!(CommandKind::from_json(kind.name(), &kind.args()), Some(kind));
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        let command = Command {
+            id: 7,
+            device_id: 1201,
+            kind: CommandKind::SetReportingInterval { seconds: 60 },
+        };
+        let payload = command.encode();
+        assert_eq!(&payload[0..8], &1201u64.to_le_bytes());
+        assert_eq!(&payload[8..16], &7u64.to_le_bytes());
+        assert_eq!(payload[16], 0x03);
+        assert_eq!(&payload[17..], &60u32.to_le_bytes());
+
+        let command = Command { kind: CommandKind::Reboot, ..command };
+        assert_eq!(command.encode().len(), 17);
+    }
+
+    #[test]
+    fn test_acknowledgement() {
+        let mut payload = vec![2];
+        payload.extend_from_slice(&7u64.to_le_bytes());
+        payload.extend_from_slice(&9u64.to_le_bytes());
+        assert_eq!(parse_acknowledgement(&payload), Ok(vec![7, 9]));
+        assert_eq!(parse_acknowledgement(&[0]), Ok(vec![]));
+        assert_eq!(parse_acknowledgement(&[]), Err(ProtocolError::BadPayloadLength));
+        assert_eq!(parse_acknowledgement(&payload[..12]), Err(ProtocolError::BadPayloadLength));
+    }
+}
@@ -6,6 +6,41 @@ pub struct ServerConfig {
     pub host: String,
     #[clap(long="lport", default_value = "11110", help="Listen Port")]
     pub port: String,
+    #[clap(long="ltimeout", default_value_t = 60, help="Informer connection idle timeout, seconds")]
+    pub idle_timeout: u64,
+    #[clap(long="lmaxconn", default_value_t = 256, help="Max concurrent informer connections and datagrams")]
+    pub max_connections: usize,
+    #[clap(long="tlscert", requires="tls_key", help="Listener TLS certificate chain, PEM")]
+    pub tls_cert: Option<String>,
+    #[clap(long="tlskey", requires="tls_cert", help="Listener TLS private key, PEM")]
+    pub tls_key: Option<String>,
+    #[clap(long="tlsca", requires="tls_cert", help="CA of informer client certificates, enables mutual TLS")]
+    pub tls_client_ca: Option<String>,
+    #[clap(long="pskfile", requires="counter_file", help="Informer pre-shared keys for authenticated frames, `<informer id> <hex key>` lines")]
+    pub psk_file: Option<String>,
+    #[clap(long="counterfile", requires="psk_file", help="File keeping last frame counters of informers, so signed frames can't be replayed after a restart")]
+    pub counter_file: Option<String>,
+    #[clap(long="limitsfile", help="Setpoint limits by device type, JSON")]
+    pub limits_file: Option<String>,
+    #[clap(long="deftemp", requires="default_humidity", help="Target temperature for devices without a usable config")]
+    pub default_temperature: Option<f32>,
+    #[clap(long="defhum", requires="default_temperature", help="Target humidity for devices without a usable config")]
+    pub default_humidity: Option<f32>,
+    #[clap(long="targetsfile", help="File keeping last delivered targets for when the database is unreachable")]
+    pub targets_file: Option<String>,
+    #[clap(long="ccapacity", default_value_t = 2000, help="Max device configs in cache")]
+    pub cache_capacity: usize,
+    #[clap(long="cttl", default_value_t = 3600, help="Device config cache lifetime, seconds")]
+    pub cache_ttl: u64,
+    #[clap(long="cgrace", default_value_t = 600, help="Serve expired device configs while the database is down, seconds")]
+    pub cache_grace: u64,
+    #[clap(long="cnegttl", default_value_t = 30, help="Cache lifetime of missing device configs, seconds")]
+    pub cache_negative_ttl: u64,
+    
+    #[clap(long="uport", default_value_t = 0, help="UDP Listen Port, disabled when 0")]
+    pub udp_port: u16,
+    #[clap(long="cport", default_value_t = 0, help="CoAP Listen Port, disabled when 0")]
+    pub coap_port: u16,
     
     #[clap(long="mhost", default_value ="127.0.0.1", help="MQTT Host")]
     pub mqtt_host: String,
@@ -29,5 +64,7 @@ pub struct ServerConfig {
     #[clap(long="pport", default_value_t = 5432, help = "Postgres port")]
     pub sql_port: u16,
     #[clap(long="pdbname", default_value="devices", help="Postgress database name")]
-    pub sql_dbname: String
+    pub sql_dbname: String,
+    #[clap(long="preconnect", default_value_t = 10, help="Postgres reconnect interval, seconds")]
+    pub sql_reconnect_interval: u64
 }
\ No newline at end of file
@@ -1,32 +1,97 @@
-use crate::device::Device;
+use async_std::task;
+use async_trait::async_trait;
+use hashlink::LruCache;
+use log::error;
 
+use crate::command::{Command, CommandKind, CommandStatus};
+use crate::device::Device;
 use crate::external::abstract_external::ExternalDatabase;
-use std::{collections::HashMap, time};
+use crate::firmware::{FirmwareChunk, FirmwareOffer};
+use std::{sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time};
+
+// Rows that are not in DeviceConfig are cached too, for a shorter time
+enum CachedConfig {
+    Config(String),
+    Missing,
+}
 
+// The device type is read on first use and dropped together with the config
 struct CacheElement {
-    pub val: String,
+    pub val: CachedConfig,
+    pub device_type: Option<Option<String>>,
     pub last_update: time::SystemTime,
+    pub refreshing: bool,
 }
 
+type Cache = Mutex<LruCache<u64, CacheElement>>;
+
+// Bumped by invalidation while the cache is locked. Results of requests
+// started before it are not stored, they may predate the change.
+type Generation = AtomicU64;
+
+#[derive(Default)]
+struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expiries: AtomicU64,
+}
+
+// Counters since start. Stale and missing configs served from the cache are
+// hits, expiries are configs found past their lifetime.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub expiries: u64,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    pub device_id: u64,
+    pub age: time::Duration,
+    pub missing: bool,
+}
+
+enum Lookup {
+    Hit(Result<String, std::io::Error>),
+    Stale { config: String, refresh: bool },
+    Miss,
+}
+
+// Device configs shared by all connections. The lock is never held across
+// a database request, concurrent misses of one device may both query it.
+// A full cache evicts the least recently used config. Expired configs are
+// served for the grace time while a background request refreshes them, so
+// they also outlive a database outage that long.
 pub struct Datacache {
-    external_channel: Box<dyn ExternalDatabase>,
-    cache: HashMap<String, CacheElement>,
+    external_channel: Arc<dyn ExternalDatabase>,
+    cache: Arc<Cache>,
+    counters: Arc<CacheCounters>,
+    generation: Arc<Generation>,
     experity_time: u64,
-    capacity: usize,
+    grace_time: u64,
+    negative_time: u64,
 }
 
 impl Datacache {
-    pub fn new(external_channel: Box<dyn ExternalDatabase>) -> Datacache {
+    pub fn new(external_channel: Arc<dyn ExternalDatabase>) -> Datacache {
         return Datacache {
             external_channel: external_channel,
-            cache: HashMap::new(),
+            cache: Arc::new(Mutex::new(LruCache::new(2000))),
+            counters: Arc::new(CacheCounters::default()),
+            generation: Arc::new(Generation::new(0)),
             experity_time: 60 * 60,
-            capacity: 2000,
+            grace_time: 10 * 60,
+            negative_time: 30,
         };
     }
 
     pub fn set_capacity(&mut self, capacity: usize) -> &mut Datacache {
-        self.capacity = capacity;
+        self.cache.lock().unwrap().set_capacity(capacity);
         self
     }
 
@@ -35,52 +100,216 @@ impl Datacache {
         self
     }
 
-    fn has_capacity(&mut self) -> bool {
-        return self.cache.len() < self.capacity;
+    pub fn set_grace_time(&mut self, grace_time: u64) -> &mut Datacache {
+        self.grace_time = grace_time;
+        self
+    }
+
+    pub fn set_negative_time(&mut self, negative_time: u64) -> &mut Datacache {
+        self.negative_time = negative_time;
+        self
     }
 
     pub fn get_size(&self) -> usize {
-        self.cache.len()
+        self.cache.lock().unwrap().len()
     }
 
-    fn cleanup(&mut self) {
-        let expired_keys: Vec<String> = self
-            .cache
+    pub fn get_stats(&self) -> CacheStats {
+        let (size, capacity) = {
+            let cache = self.cache.lock().unwrap();
+            (cache.len(), cache.capacity())
+        };
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            expiries: self.counters.expiries.load(Ordering::Relaxed),
+            size,
+            capacity,
+        }
+    }
+
+    // Least recently used first
+    pub fn get_entries(&self) -> Vec<CacheEntry> {
+        self.cache
+            .lock()
+            .unwrap()
             .iter()
-            .filter(|(k, v)| v.last_update.elapsed().unwrap().as_secs() >= self.experity_time)
-            .map(|(k, v)| k.clone())
-            .collect();
-        expired_keys.iter().for_each(|k| {
-            self.cache.remove(k);
-        })
-    }
-
-    pub async fn set_config(&mut self, device: &mut dyn Device) -> Result<(), std::io::Error> {
-        let name = device.get_name();
-        match self.cache.get(&name) {
-            Some(device_raw_config) => {
-                if device_raw_config.last_update.elapsed().unwrap().as_secs() < self.experity_time {
-                    device.set_config(&device_raw_config.val);
-                    return Ok(());
+            .map(|(device_id, element)| CacheEntry {
+                device_id: *device_id,
+                age: element.last_update.elapsed().unwrap_or_default(),
+                missing: matches!(element.val, CachedConfig::Missing),
+            })
+            .collect()
+    }
+
+    // Next request of the device reads its config from the database
+    pub fn invalidate(&self, device_id: u64) -> bool {
+        let mut cache = self.cache.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        cache.remove(&device_id).is_some()
+    }
+
+    pub fn invalidate_all(&self) -> usize {
+        let mut cache = self.cache.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        let size = cache.len();
+        cache.clear();
+        size
+    }
+
+    pub async fn set_config(&self, device: &mut dyn Device) -> Result<(), std::io::Error> {
+        let config = self.get_config(device.get_id()).await?;
+        device.set_config(&config)?;
+        Ok(())
+    }
+
+    async fn get_config(&self, device_id: u64) -> Result<String, std::io::Error> {
+        match self.lookup(device_id) {
+            Lookup::Hit(result) => {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                return result;
+            }
+            Lookup::Stale { config, refresh } => {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                if refresh {
+                    self.refresh(device_id);
                 }
+                return Ok(config);
+            }
+            Lookup::Miss => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
             }
-            None => {}
-        }
-        let config = self.external_channel.get_device_config(&name).await?;
-        device.set_config(&config);
-        if !self.has_capacity() {
-            self.cleanup();
-        }
-        if self.has_capacity() {
-            self.cache.insert(
-                name,
-                CacheElement {
-                    val: config,
-                    last_update: time::SystemTime::now(),
-                },
-            );
         }
-        Ok(())
+        let generation = self.generation.load(Ordering::SeqCst);
+        let result = self.external_channel.get_device_config(device_id).await;
+        store(&self.cache, &self.counters, &self.generation, generation, device_id, &result);
+        result
+    }
+
+    async fn get_type(&self, device_id: u64) -> Result<Option<String>, std::io::Error> {
+        let cached = self.cache.lock().unwrap().peek(&device_id).and_then(|element| element.device_type.clone());
+        if let Some(device_type) = cached {
+            return Ok(device_type);
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        let device_type = self.external_channel.get_device_type(device_id).await?;
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(element) = cache.peek_mut(&device_id).filter(|_| self.generation.load(Ordering::SeqCst) == generation) {
+            element.device_type = Some(device_type.clone());
+        }
+        Ok(device_type)
+    }
+
+    fn lookup(&self, device_id: u64) -> Lookup {
+        let mut cache = self.cache.lock().unwrap();
+        let element = match cache.get_mut(&device_id) {
+            Some(element) => element,
+            None => return Lookup::Miss,
+        };
+        let age = element.last_update.elapsed().unwrap_or_default().as_secs();
+        let lookup = match &element.val {
+            CachedConfig::Config(config) if age < self.experity_time => Lookup::Hit(Ok(config.clone())),
+            CachedConfig::Config(config) if age < self.experity_time + self.grace_time => {
+                let refresh = !element.refreshing;
+                element.refreshing = true;
+                if refresh {
+                    self.counters.expiries.fetch_add(1, Ordering::Relaxed);
+                }
+                Lookup::Stale { config: config.clone(), refresh }
+            }
+            CachedConfig::Missing if age < self.negative_time => Lookup::Hit(Err(no_config(device_id))),
+            _ => Lookup::Miss,
+        };
+        if let Lookup::Miss = lookup {
+            cache.remove(&device_id);
+            self.counters.expiries.fetch_add(1, Ordering::Relaxed);
+        }
+        lookup
+    }
+
+    fn refresh(&self, device_id: u64) {
+        let external_channel = self.external_channel.clone();
+        let cache = self.cache.clone();
+        let counters = self.counters.clone();
+        let generation = self.generation.clone();
+        let started = generation.load(Ordering::SeqCst);
+        task::spawn(async move {
+            let result = external_channel.get_device_config(device_id).await;
+            if let Err(e) = &result {
+                error!("Can't refresh config of device {}: {}", device_id, e);
+            }
+            store(&cache, &counters, &generation, started, device_id, &result);
+        });
+    }
+}
+
+fn no_config(device_id: u64) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, format!("No config for device {}", device_id))
+}
+
+// Database errors other than a missing row keep the stale config until its grace time ends
+fn store(cache: &Cache, counters: &CacheCounters, generation: &Generation, started: u64, device_id: u64, result: &Result<String, std::io::Error>) {
+    let val = match result {
+        Ok(config) => CachedConfig::Config(config.clone()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => CachedConfig::Missing,
+        Err(_) => {
+            if let Some(element) = cache.lock().unwrap().peek_mut(&device_id) {
+                element.refreshing = false;
+            }
+            return;
+        }
+    };
+    let mut cache = cache.lock().unwrap();
+    if generation.load(Ordering::SeqCst) != started {
+        if let Some(element) = cache.peek_mut(&device_id) {
+            element.refreshing = false;
+        }
+        return;
+    }
+    if cache.peek(&device_id).is_none() && cache.len() >= cache.capacity() {
+        counters.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+    cache.insert(
+        device_id,
+        CacheElement {
+            val,
+            device_type: None,
+            last_update: time::SystemTime::now(),
+            refreshing: false,
+        },
+    );
+}
+
+// Stands in for the database in the listeners, only configs and types are cached
+#[async_trait]
+impl ExternalDatabase for Datacache {
+    async fn get_device_config(&self, device_id: u64) -> Result<String, std::io::Error> {
+        self.get_config(device_id).await
+    }
+    async fn get_device_type(&self, device_id: u64) -> Result<Option<String>, std::io::Error> {
+        self.get_type(device_id).await
+    }
+    async fn get_pending_commands(&self, device_ids: &[u64]) -> Result<Vec<Command>, std::io::Error> {
+        self.external_channel.get_pending_commands(device_ids).await
+    }
+    async fn get_commands(&self, command_ids: &[u64]) -> Result<Vec<Command>, std::io::Error> {
+        self.external_channel.get_commands(command_ids).await
+    }
+    async fn set_command_status(&self, command_ids: &[u64], status: CommandStatus) -> Result<(), std::io::Error> {
+        self.external_channel.set_command_status(command_ids, status).await
+    }
+    async fn enqueue_command(&self, device_id: u64, kind: CommandKind) -> Result<u64, std::io::Error> {
+        self.external_channel.enqueue_command(device_id, kind).await
+    }
+    async fn report_firmware_version(&self, device_id: u64, version: u32) -> Result<(), std::io::Error> {
+        self.external_channel.report_firmware_version(device_id, version).await
+    }
+    async fn get_firmware_offer(&self, device_id: u64) -> Result<Option<FirmwareOffer>, std::io::Error> {
+        self.external_channel.get_firmware_offer(device_id).await
+    }
+    async fn get_firmware_chunk(&self, device_id: u64, offset: u32, length: u16) -> Result<Option<FirmwareChunk>, std::io::Error> {
+        self.external_channel.get_firmware_chunk(device_id, offset, length).await
     }
 }
 
@@ -88,15 +317,14 @@ impl Datacache {
 mod tests {
     use async_std::task::block_on;
     use async_trait::async_trait;
-    use futures::TryFutureExt;
-    use std::{borrow::BorrowMut, collections::HashMap, sync::{Arc, Mutex}};
+    use std::{collections::HashMap, io, sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex}, thread, time::Duration};
 
-    use crate::{device::Device, external::abstract_external::ExternalDatabase};
+    use crate::{device::{ConfigError, Device, HardDevice}, external::abstract_external::ExternalDatabase};
 
-    use super::Datacache;
+    use super::{CacheStats, Datacache};
 
     struct MockExternalDatabaseInstance {
-        pub data: HashMap<String, String>,
+        pub data: HashMap<u64, String>,
         pub counter: u16,
     }
 
@@ -106,11 +334,16 @@ mod tests {
 
 
     struct MockDevice {
+        pub id: u64,
         pub name: String,
         pub config: String
     }
 
     impl Device for MockDevice {
+        fn get_id(&self) -> u64 {
+            self.id
+        }
+
         fn get_name(&self) -> String {
             self.name.clone()
         }
@@ -119,21 +352,65 @@ mod tests {
             self.config.clone()
         }
 
-        fn set_config(&mut self, config: &String) {
+        fn set_config(&mut self, config: &String) -> Result<(), ConfigError> {
             self.config = config.clone();
+            Ok(())
         }
 
-        fn target_as_bytes(&self) -> Vec<u8> {
-            Vec::new()
+        fn target_as_bytes(&self) -> Result<Vec<u8>, ConfigError> {
+            Ok(Vec::new())
         }
         
     }
 
+    // Database with a missing row for unknown devices that can go down.
+    // A held database answers only after it is released.
+    #[derive(Default)]
+    struct FlakyExternalDatabase {
+        configs: Mutex<HashMap<u64, String>>,
+        types: Mutex<HashMap<u64, String>>,
+        down: AtomicBool,
+        held: AtomicBool,
+        counter: AtomicUsize,
+        type_counter: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ExternalDatabase for FlakyExternalDatabase {
+        async fn get_device_config(&self, device_id: u64) -> Result<String, io::Error> {
+            let config = self.configs.lock().unwrap().get(&device_id).cloned();
+            self.counter.fetch_add(1, Ordering::SeqCst);
+            while self.held.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            if self.down.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "Database is down"));
+            }
+            config.ok_or(io::Error::new(io::ErrorKind::NotFound, "No config"))
+        }
+        async fn get_device_type(&self, device_id: u64) -> Result<Option<String>, io::Error> {
+            self.type_counter.fetch_add(1, Ordering::SeqCst);
+            if self.down.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "Database is down"));
+            }
+            Ok(self.types.lock().unwrap().get(&device_id).cloned())
+        }
+    }
+
+    fn wait_for(counter: &AtomicUsize, value: usize) {
+        for _ in 0..100 {
+            if counter.load(Ordering::SeqCst) >= value {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
     #[async_trait]
     impl ExternalDatabase for MockExternalDatabase {
-        async fn get_device_config(&mut self, key: &String) -> Result<String, std::io::Error> {
+        async fn get_device_config(&self, device_id: u64) -> Result<String, std::io::Error> {
             self.instance.lock().unwrap().counter+=1;
-            match self.instance.lock().unwrap().data.get(key) {
+            match self.instance.lock().unwrap().data.get(&device_id) {
                 Some(data) => Ok(data.clone()),
                 None => Ok(
                     "unknwon_config".to_owned()
@@ -151,9 +428,9 @@ mod tests {
 
         let external_database = MockExternalDatabase{instance: external_database_instance.clone()};
 
-        let mut cache = Datacache::new(Box::new(external_database));
-        let mut device: Box<dyn Device> = Box::new(MockDevice {name: "Device1".to_owned(), config: "".to_owned()});
-        external_database_instance.lock().unwrap().data.insert("Device1".to_owned(), "config1".to_owned());
+        let cache = Datacache::new(Arc::new(external_database));
+        let mut device: Box<dyn Device> = Box::new(MockDevice {id: 1, name: "Device1".to_owned(), config: "".to_owned()});
+        external_database_instance.lock().unwrap().data.insert(1, "config1".to_owned());
         for _ in 1..10 {
             block_on(cache.set_config(&mut *device)).unwrap();
             assert_eq!(device.as_json() , "config1");
@@ -168,16 +445,40 @@ mod tests {
             counter: 0,
         }));
         let external_database = MockExternalDatabase{instance: external_database_instance.clone()};
-        let mut cache = Datacache::new(Box::new(external_database));
+        let mut cache = Datacache::new(Arc::new(external_database));
         cache.set_capacity(5);
         
-        for _ in 0..2 {
-            for i in 0..10 {
-                let mut device: Box<dyn Device> = Box::new(MockDevice{name: format!("device: {}", i), config: "".to_owned()});
+        for i in 0..5 {
+            let mut device: Box<dyn Device> = Box::new(MockDevice{id: i, name: format!("device: {}", i), config: "".to_owned()});
+            block_on(cache.set_config(&mut *device)).unwrap();
+        }
+        for _ in 0..3 {
+            for i in 5..10 {
+                let mut device: Box<dyn Device> = Box::new(MockDevice{id: i, name: format!("device: {}", i), config: "".to_owned()});
                 block_on(cache.set_config(&mut *device)).unwrap();
             }
         }
-        assert_eq!(external_database_instance.lock().unwrap().counter, 15, "Capacity for 5 elements only");
+        assert_eq!(cache.get_size(), 5, "Capacity for 5 elements only");
+        assert_eq!(external_database_instance.lock().unwrap().counter, 10, "Recent devices replace old ones");
+    }
+
+    #[test]
+    fn test_lru() {
+        let external_database_instance = Arc::new(Mutex::new(MockExternalDatabaseInstance {
+            data: HashMap::new(),
+            counter: 0,
+        }));
+        let external_database = MockExternalDatabase{instance: external_database_instance.clone()};
+        let mut cache = Datacache::new(Arc::new(external_database));
+        cache.set_capacity(2);
+
+        let mut hot: Box<dyn Device> = Box::new(MockDevice{id: 1000, name: "hot".to_owned(), config: "".to_owned()});
+        for i in 0..10 {
+            block_on(cache.set_config(&mut *hot)).unwrap();
+            let mut device: Box<dyn Device> = Box::new(MockDevice{id: i, name: format!("device: {}", i), config: "".to_owned()});
+            block_on(cache.set_config(&mut *device)).unwrap();
+        }
+        assert_eq!(external_database_instance.lock().unwrap().counter, 11, "Hot device stays cached");
     }
 
     #[test]
@@ -187,16 +488,16 @@ mod tests {
             counter: 0,
         }));
         let external_database = MockExternalDatabase{instance: external_database_instance.clone()};
-        let mut cache = Datacache::new(Box::new(external_database));
-        cache.set_capacity(2).set_experity_time(0);
+        let mut cache = Datacache::new(Arc::new(external_database));
+        cache.set_capacity(2).set_experity_time(0).set_grace_time(0);
 
 
-        let mut device1: Box<dyn Device> = Box::new(MockDevice{name: "device1".to_owned(), config: "".to_owned()});
-        let mut device2: Box<dyn Device> = Box::new(MockDevice{name: "device2".to_owned(), config: "".to_owned()});
-        let mut device3: Box<dyn Device> = Box::new(MockDevice{name: "device3".to_owned(), config: "".to_owned()});
-        external_database_instance.lock().unwrap().data.insert(device1.get_name(), "".to_owned());
-        external_database_instance.lock().unwrap().data.insert(device2.get_name(), "".to_owned());
-        external_database_instance.lock().unwrap().data.insert(device3.get_name(), "".to_owned());
+        let mut device1: Box<dyn Device> = Box::new(MockDevice{id: 1, name: "device1".to_owned(), config: "".to_owned()});
+        let mut device2: Box<dyn Device> = Box::new(MockDevice{id: 2, name: "device2".to_owned(), config: "".to_owned()});
+        let mut device3: Box<dyn Device> = Box::new(MockDevice{id: 3, name: "device3".to_owned(), config: "".to_owned()});
+        external_database_instance.lock().unwrap().data.insert(device1.get_id(), "".to_owned());
+        external_database_instance.lock().unwrap().data.insert(device2.get_id(), "".to_owned());
+        external_database_instance.lock().unwrap().data.insert(device3.get_id(), "".to_owned());
 
         block_on(cache.set_config(&mut *device1)).unwrap();
         assert_eq!(cache.get_size(), 1, "First element append");
@@ -205,6 +506,218 @@ mod tests {
         assert_eq!(cache.get_size(), 2, "Second element append");
         
         block_on(cache.set_config(&mut *device3)).unwrap();
-        assert_eq!(cache.get_size(), 1, "Remove first and second element as capacity is exceed, append third element");
+        assert_eq!(cache.get_size(), 2, "Remove least recently used element as capacity is exceed, append third element");
+
+        block_on(cache.set_config(&mut *device3)).unwrap();
+        assert_eq!(external_database_instance.lock().unwrap().counter, 4, "Expired element is requested again");
+    }
+
+    #[test]
+    fn test_shared() {
+        let external_database_instance = Arc::new(Mutex::new(MockExternalDatabaseInstance {
+            data: HashMap::new(),
+            counter: 0,
+        }));
+        external_database_instance.lock().unwrap().data.insert(1201, "config1".to_owned());
+        let external_database = MockExternalDatabase{instance: external_database_instance.clone()};
+        let cache: Arc<dyn ExternalDatabase> = Arc::new(Datacache::new(Arc::new(external_database)));
+        block_on(cache.get_device_config(1201)).unwrap();
+
+        let threads: Vec<_> = (0..4).map(|_| {
+            let cache = cache.clone();
+            thread::spawn(move || block_on(cache.get_device_config(1201)).unwrap())
+        }).collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), "config1");
+        }
+        assert_eq!(external_database_instance.lock().unwrap().counter, 1, "Connections share the cache");
+    }
+
+    #[test]
+    fn test_hard_device() {
+        let external_database_instance = Arc::new(Mutex::new(MockExternalDatabaseInstance {
+            data: HashMap::new(),
+            counter: 0,
+        }));
+        external_database_instance.lock().unwrap().data.insert(1201, "{\"temperature\": 4.5, \"humidity\": 55.0}".to_owned());
+        let external_database = MockExternalDatabase{instance: external_database_instance.clone()};
+        let cache = Datacache::new(Arc::new(external_database));
+
+        // Names are "Device 1201", DeviceConfig rows are found by id
+        let mut hdevice = HardDevice::from_device_id(1201).unwrap();
+        block_on(cache.set_config(&mut hdevice)).unwrap();
+        assert_eq!(hdevice.get_target_temperature(), Some(4.5));
+        let mut hdevice = HardDevice::from_device_id(1201).unwrap();
+        block_on(cache.set_config(&mut hdevice)).unwrap();
+        assert_eq!(hdevice.get_target_humidity(), Some(55.0));
+        assert_eq!(external_database_instance.lock().unwrap().counter, 1);
+
+        let mut hdevice = HardDevice::from_device_id(1202).unwrap();
+        assert!(block_on(cache.set_config(&mut hdevice)).is_err(), "No config row for the id");
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let database = Arc::new(FlakyExternalDatabase::default());
+        database.configs.lock().unwrap().insert(1201, "config1".to_owned());
+        let cache = Datacache::new(database.clone());
+        assert_eq!(block_on(cache.get_device_config(1201)).unwrap(), "config1");
+        assert!(block_on(cache.get_device_config(1202)).is_err());
+
+        database.configs.lock().unwrap().insert(1201, "config2".to_owned());
+        database.configs.lock().unwrap().insert(1202, "config3".to_owned());
+        cache.invalidate(1201);
+        cache.invalidate(1202);
+        cache.invalidate(1203);
+        assert_eq!(block_on(cache.get_device_config(1201)).unwrap(), "config2");
+        assert_eq!(block_on(cache.get_device_config(1202)).unwrap(), "config3", "Missing row is dropped too");
+        assert_eq!(database.counter.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_stale_while_revalidate() {
+        let database = Arc::new(FlakyExternalDatabase::default());
+        database.configs.lock().unwrap().insert(1201, "config1".to_owned());
+        let mut cache = Datacache::new(database.clone());
+        cache.set_experity_time(0);
+
+        assert_eq!(block_on(cache.get_device_config(1201)).unwrap(), "config1");
+        assert_eq!(database.counter.load(Ordering::SeqCst), 1);
+        database.configs.lock().unwrap().insert(1201, "config2".to_owned());
+        assert_eq!(block_on(cache.get_device_config(1201)).unwrap(), "config1", "Stale config is served at once");
+        wait_for(&database.counter, 2);
+        let mut config = String::new();
+        for _ in 0..100 {
+            config = block_on(cache.get_device_config(1201)).unwrap();
+            if config == "config2" {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(config, "config2", "Refreshed in background");
+    }
+
+    #[test]
+    fn test_grace_time() {
+        let database = Arc::new(FlakyExternalDatabase::default());
+        database.configs.lock().unwrap().insert(1201, "config1".to_owned());
+        let mut cache = Datacache::new(database.clone());
+        cache.set_experity_time(0);
+        block_on(cache.get_device_config(1201)).unwrap();
+
+        database.down.store(true, Ordering::SeqCst);
+        for _ in 0..3 {
+            assert_eq!(block_on(cache.get_device_config(1201)).unwrap(), "config1", "Served while database is down");
+            wait_for(&database.counter, 2);
+        }
+
+        cache.set_grace_time(0);
+        assert_eq!(block_on(cache.get_device_config(1201)).unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn test_negative_caching() {
+        let database = Arc::new(FlakyExternalDatabase::default());
+        let mut cache = Datacache::new(database.clone());
+        for _ in 0..3 {
+            assert_eq!(block_on(cache.get_device_config(1202)).unwrap_err().kind(), io::ErrorKind::NotFound);
+        }
+        assert_eq!(database.counter.load(Ordering::SeqCst), 1, "Missing row is cached");
+
+        cache.set_negative_time(0);
+        database.configs.lock().unwrap().insert(1202, "config1".to_owned());
+        assert_eq!(block_on(cache.get_device_config(1202)).unwrap(), "config1");
+
+        database.down.store(true, Ordering::SeqCst);
+        assert!(block_on(cache.get_device_config(1203)).is_err());
+        assert_eq!(cache.get_size(), 1, "Database errors are not cached");
+    }
+
+    #[test]
+    fn test_stats() {
+        let database = Arc::new(FlakyExternalDatabase::default());
+        database.configs.lock().unwrap().insert(1201, "config1".to_owned());
+        database.configs.lock().unwrap().insert(1202, "config2".to_owned());
+        let mut cache = Datacache::new(database.clone());
+        cache.set_capacity(2);
+
+        block_on(cache.get_device_config(1201)).unwrap();
+        block_on(cache.get_device_config(1201)).unwrap();
+        block_on(cache.get_device_config(1202)).unwrap();
+        assert!(block_on(cache.get_device_config(1203)).is_err());
+        assert!(block_on(cache.get_device_config(1203)).is_err());
+        assert_eq!(cache.get_stats(), CacheStats { hits: 2, misses: 3, evictions: 1, expiries: 0, size: 2, capacity: 2 });
+
+        let entries = cache.get_entries();
+        assert_eq!(entries.iter().map(|e| (e.device_id, e.missing)).collect::<Vec<_>>(), vec![(1202, false), (1203, true)]);
+        assert!(entries[0].age < Duration::from_secs(1));
+
+        cache.set_experity_time(0).set_grace_time(0).set_negative_time(0);
+        block_on(cache.get_device_config(1202)).unwrap();
+        assert_eq!(cache.get_stats().expiries, 1);
+        assert_eq!(cache.get_stats().misses, 4);
+    }
+
+    #[test]
+    fn test_invalidate_all() {
+        let database = Arc::new(FlakyExternalDatabase::default());
+        database.configs.lock().unwrap().insert(1201, "config1".to_owned());
+        let cache = Datacache::new(database.clone());
+        block_on(cache.get_device_config(1201)).unwrap();
+        assert!(block_on(cache.get_device_config(1202)).is_err());
+
+        assert!(cache.invalidate(1201));
+        assert!(!cache.invalidate(1201));
+        block_on(cache.get_device_config(1201)).unwrap();
+        assert_eq!(cache.invalidate_all(), 2);
+        assert_eq!(cache.get_size(), 0);
+        assert_eq!(cache.get_stats().evictions, 0, "Invalidated configs are not evictions");
+    }
+
+    #[test]
+    fn test_device_type() {
+        let database = Arc::new(FlakyExternalDatabase::default());
+        database.configs.lock().unwrap().insert(1201, "config1".to_owned());
+        database.types.lock().unwrap().insert(1201, "fridge".to_owned());
+        let cache = Datacache::new(database.clone());
+        block_on(cache.get_device_config(1201)).unwrap();
+        block_on(cache.get_device_config(1202)).unwrap_err();
+        for _ in 0..3 {
+            assert_eq!(block_on(cache.get_device_type(1201)).unwrap().as_deref(), Some("fridge"));
+            assert_eq!(block_on(cache.get_device_type(1202)).unwrap(), None);
+        }
+        assert_eq!(database.type_counter.load(Ordering::SeqCst), 2, "Types are cached with the config");
+
+        database.types.lock().unwrap().insert(1201, "freezer".to_owned());
+        cache.invalidate(1201);
+        block_on(cache.get_device_config(1201)).unwrap();
+        assert_eq!(block_on(cache.get_device_type(1201)).unwrap().as_deref(), Some("freezer"));
+
+        database.down.store(true, Ordering::SeqCst);
+        cache.invalidate(1201);
+        assert!(block_on(cache.get_device_type(1201)).is_err());
+        assert_eq!(block_on(cache.get_device_type(1202)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_invalidate_during_request() {
+        let database = Arc::new(FlakyExternalDatabase::default());
+        database.configs.lock().unwrap().insert(1201, "config1".to_owned());
+        let cache = Arc::new(Datacache::new(database.clone()));
+
+        database.held.store(true, Ordering::SeqCst);
+        let request = {
+            let cache = cache.clone();
+            thread::spawn(move || block_on(cache.get_device_config(1201)).unwrap())
+        };
+        wait_for(&database.counter, 1);
+        database.configs.lock().unwrap().insert(1201, "config2".to_owned());
+        cache.invalidate(1201);
+        database.held.store(false, Ordering::SeqCst);
+        assert_eq!(request.join().unwrap(), "config1");
+        assert_eq!(cache.get_size(), 0, "Config read before the change is not stored");
+        assert_eq!(block_on(cache.get_device_config(1201)).unwrap(), "config2");
+        assert_eq!(block_on(cache.get_device_config(1201)).unwrap(), "config2");
+        assert_eq!(database.counter.load(Ordering::SeqCst), 2);
     }
 }
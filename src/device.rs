@@ -1,18 +1,50 @@
-use std::{error::Error, mem, usize};
+use std::{error::Error, fmt, io, mem, usize};
 use serde::{Deserialize, Serialize};
 use serde_json;
 
+use crate::device_id::DeviceAddress;
+use crate::protocol::{self, Frame, ProtocolError, FRAME_TYPE_TELEMETRY, FRAME_TYPE_TELEMETRY_BATCH, FRAME_TYPE_TELEMETRY_TLV, FRAME_TYPE_TELEMETRY_WIDE};
+use crate::reading::{Channel, Reading};
+
 pub struct DeviceConfig {
     temperature: f32,
     humidity: f32
 }
 
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    // DeviceConfig value is not {"temperature": .., "humidity": ..}
+    Malformed(String),
+    NoTargets { device_id: u64 },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Malformed(e) => write!(f, "Malformed device config: {}", e),
+            ConfigError::NoTargets { device_id } => write!(f, "Device {} has no targets", device_id),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    fn from(e: ConfigError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct HardDevice {
-    id: u32,
+    id: u64,
+    address: DeviceAddress,
     name: String,
-    temperature: f32,
-    humidity: f32,
+    readings: Vec<Reading>,
+    sequence: Option<u32>,
+    device_time: Option<u64>,
+    received_at: Option<u64>,
+    backfilled: bool,
     target_temperature: Option<f32>,
     target_humidity: Option<f32>
 }
@@ -25,63 +57,186 @@ struct MongoStructure {
 
 
 pub trait Device {
+    // Key of the device in DeviceConfig and in the config cache
+    fn get_id(&self) -> u64;
     fn get_name(&self) -> String;
     fn as_json(&self) -> String;
-    fn set_config(&mut self, config: &String);
-    fn target_as_bytes(&self) -> Vec<u8>;
+    fn set_config(&mut self, config: &String) -> Result<(), ConfigError>;
+    fn target_as_bytes(&self) -> Result<Vec<u8>, ConfigError>;
 }
 
 impl Device for HardDevice {
 
+    fn get_id(&self) -> u64 {
+        self.id
+    }
+
     fn get_name(&self) -> String {
         self.name.clone()
     }
 
     fn as_json(&self) -> String {
-        format!("{{\"id\":{}, \"name\":\"{}\", \"temperature\":{}, \"humidity\":{}}}", self.id, self.name, self.temperature, self.humidity)
+        let mut json = format!("{{\"id\":{}, \"name\":\"{}\"", self.id, self.name);
+        for reading in &self.readings {
+            json.push_str(&format!(", \"{}\":{}", reading.channel.name(), reading.value));
+        }
+        if let Some(sequence) = self.sequence {
+            json.push_str(&format!(", \"sequence\":{}", sequence));
+        }
+        if let Some(device_time) = self.device_time {
+            json.push_str(&format!(", \"device_time\":{}", device_time));
+        }
+        if let Some(received_at) = self.received_at {
+            json.push_str(&format!(", \"received_at\":{}", received_at));
+        }
+        if self.backfilled {
+            json.push_str(", \"backfilled\":true");
+        }
+        json.push('}');
+        json
     }
 
-    fn set_config(&mut self, config: &String) {
-        let mongo_config: MongoStructure = serde_json::from_str(config).unwrap();
+    fn set_config(&mut self, config: &String) -> Result<(), ConfigError> {
+        let mongo_config: MongoStructure = serde_json::from_str(config).map_err(|e| ConfigError::Malformed(e.to_string()))?;
         self.target_humidity = Some(mongo_config.humidity);
         self.target_temperature = Some(mongo_config.temperature);
+        Ok(())
     }
 
-    fn target_as_bytes(&self) -> Vec<u8> {
+    fn target_as_bytes(&self) -> Result<Vec<u8>, ConfigError> {
+        let (target_humidity, target_temperature) = match (self.target_humidity, self.target_temperature) {
+            (Some(target_humidity), Some(target_temperature)) => (target_humidity, target_temperature),
+            _ => return Err(ConfigError::NoTargets { device_id: self.id }),
+        };
         let mut buf_vec = Vec::new();
-        buf_vec.extend_from_slice(&self.id.to_le_bytes());
-        buf_vec.extend_from_slice(&self.target_humidity.unwrap().to_le_bytes());
-        buf_vec.extend_from_slice(&self.target_temperature.unwrap().to_le_bytes());
-        buf_vec
+        match self.address {
+            DeviceAddress::Legacy { .. } => buf_vec.extend_from_slice(&(self.id as u32).to_le_bytes()),
+            DeviceAddress::Wide { informer_id, sensor_id } => {
+                buf_vec.extend_from_slice(&informer_id.to_le_bytes());
+                buf_vec.extend_from_slice(&sensor_id.to_le_bytes());
+            }
+        }
+        buf_vec.extend_from_slice(&target_humidity.to_le_bytes());
+        buf_vec.extend_from_slice(&target_temperature.to_le_bytes());
+        Ok(buf_vec)
     }
 }
 
+pub const HEADER_SIZE: usize = 2;
+pub const DEVICE_DATA_SIZE: usize = 8;
+pub const WIDE_HEADER_SIZE: usize = 5;
+pub const WIDE_DEVICE_DATA_SIZE: usize = 10;
+pub const TLV_HEADER_SIZE: usize = 4;
+pub const TLV_TAG_SENSOR: u8 = 0x00;
+// Frame level records, they apply to every device of the frame wherever they are
+pub const TLV_TAG_SEQUENCE: u8 = 0xF0;
+pub const TLV_TAG_DEVICE_TIME: u8 = 0xF1;
+// Batch frames only, starts the next sample of the current sensor
+pub const TLV_TAG_SAMPLE_TIME: u8 = 0xF2;
+pub const MAX_DEVICES_PER_FRAME: usize = u8::MAX as usize;
+
 impl HardDevice {
-    pub fn factory(buf: &[u8], len: usize) -> Result<Vec<HardDevice>, Box<dyn Error>> {
+    // Full size of the frame starting at buf[0], or None while the header is incomplete
+    pub fn frame_length(buf: &[u8]) -> Option<usize> {
+        if buf.len() < HEADER_SIZE {
+            return None;
+        }
+        Some(HEADER_SIZE + buf[1] as usize * DEVICE_DATA_SIZE)
+    }
+
+    pub fn factory(buf: &[u8], len: usize) -> Result<Vec<HardDevice>, ProtocolError> {
         println!("In factory");
-        if len < 2 {
-            return Err("Not enough data to parse header".into());
+        let mut devices = match protocol::decode(&buf[..len])? {
+            Frame::Legacy(body) => HardDevice::parse_telemetry(body, body.len()),
+            Frame::V2 { frame_type: FRAME_TYPE_TELEMETRY, payload } => {
+                HardDevice::parse_telemetry(payload, payload.len()).map_err(ProtocolError::within_frame)
+            }
+            Frame::V2 { frame_type: FRAME_TYPE_TELEMETRY_WIDE, payload } => {
+                HardDevice::parse_wide_telemetry(payload).map_err(ProtocolError::within_frame)
+            }
+            Frame::V2 { frame_type: FRAME_TYPE_TELEMETRY_TLV, payload } => HardDevice::parse_tlv_telemetry(payload, false),
+            Frame::V2 { frame_type: FRAME_TYPE_TELEMETRY_BATCH, payload } => HardDevice::parse_tlv_telemetry(payload, true),
+            Frame::V2 { frame_type, .. } => Err(ProtocolError::UnsupportedFrameType(frame_type)),
+        }?;
+        HardDevice::receive(&mut devices)?;
+        Ok(devices)
+    }
+
+    // Telemetry document, see document.rs for the layout
+    pub fn from_document(document: &serde_json::Value) -> Result<Vec<HardDevice>, ProtocolError> {
+        let informer_id = document["informer"].as_u64().and_then(|id| u32::try_from(id).ok()).ok_or(ProtocolError::BadDocument)?;
+        let sequence = match &document["sequence"] {
+            serde_json::Value::Null => None,
+            sequence => Some(sequence.as_u64().and_then(|s| u32::try_from(s).ok()).ok_or(ProtocolError::BadDocument)?),
+        };
+        let device_time = match &document["device_time"] {
+            serde_json::Value::Null => None,
+            device_time => Some(device_time.as_u64().ok_or(ProtocolError::BadDocument)?),
+        };
+        let entries = document["devices"].as_array().ok_or(ProtocolError::BadDocument)?;
+        if entries.len() > MAX_DEVICES_PER_FRAME {
+            return Err(ProtocolError::TooManyDevices);
+        }
+
+        let mut devices = Vec::new();
+        for entry in entries {
+            let entry = entry.as_object().ok_or(ProtocolError::BadDocument)?;
+            let sensor_id = entry.get("sensor").and_then(|s| s.as_u64()).and_then(|s| u16::try_from(s).ok());
+            let mut device = HardDevice::new_sensor(DeviceAddress::Wide {
+                informer_id,
+                sensor_id: sensor_id.ok_or(ProtocolError::BadDocument)?,
+            });
+            for (name, value) in entry.iter().filter(|(name, _)| name.as_str() != "sensor") {
+                let channel = Channel::from_name(name).ok_or(ProtocolError::BadDocument)?;
+                let value = value.as_f64().ok_or(ProtocolError::BadDocument)?;
+                device.readings.push(Reading::new(channel, value as f32));
+            }
+            device.sequence = sequence;
+            device.device_time = device_time;
+            devices.push(device);
+        }
+        HardDevice::receive(&mut devices)?;
+        Ok(devices)
+    }
+
+    fn receive(devices: &mut [HardDevice]) -> Result<(), ProtocolError> {
+        let received_at = chrono::Utc::now().timestamp_millis() as u64;
+        if let Some(device) = devices.iter().find(|d| d.readings.iter().any(|r| !r.value.is_finite())) {
+            return Err(ProtocolError::NonFiniteReading { device_id: device.id });
+        }
+        devices.iter_mut().for_each(|d| d.received_at = Some(received_at));
+        Ok(())
+    }
+
+    fn parse_telemetry(buf: &[u8], len: usize) -> Result<Vec<HardDevice>, ProtocolError> {
+        if len < HEADER_SIZE {
+            return Err(ProtocolError::TruncatedHeader);
         }
         println!("Parse devices");
         let mut devices = Vec::new();
-        let informer_id: u32 = buf[0] as u32;
+        let informer_id = buf[0];
         let devices_count = buf[1];
         println!("Received {} devices", devices_count);
-        if len < 2 + devices_count as usize * 8 {
-            return Err("Not enough data to parse devices".into());
+        if len < HEADER_SIZE + devices_count as usize * DEVICE_DATA_SIZE {
+            return Err(ProtocolError::TruncatedPayload);
         }
 
         for i in 0..devices_count {
-            let device_data = &buf[2 + i as usize * 8..2 + (i + 1) as usize * 8];
+            let offset = HEADER_SIZE + i as usize * DEVICE_DATA_SIZE;
+            let device_data = &buf[offset..offset + DEVICE_DATA_SIZE];
             let temperature = f32::from_le_bytes(device_data[0..4].try_into().unwrap());
             let humidity = f32::from_le_bytes(device_data[4..8].try_into().unwrap());
-            let device_id = (informer_id * 100 + (i as u32) + 1);
+            let address = DeviceAddress::Legacy { informer_id, index: i };
+            let device_id = address.device_id();
             devices.push(
                 HardDevice {
                     id: device_id,
+                    address,
                     name: format!("Device {}", device_id),
-                    temperature: temperature,
-                    humidity: humidity,
+                    readings: vec![
+                        Reading::new(Channel::Temperature, temperature),
+                        Reading::new(Channel::Humidity, humidity),
+                    ],
                     ..Default::default()
                 }
             );
@@ -90,18 +245,192 @@ impl HardDevice {
         Ok(devices)
     }
 
+    // [informer_id: u32][count: u8][(sensor_id: u16, temperature: f32, humidity: f32) * count]
+    fn parse_wide_telemetry(buf: &[u8]) -> Result<Vec<HardDevice>, ProtocolError> {
+        if buf.len() < WIDE_HEADER_SIZE {
+            return Err(ProtocolError::TruncatedHeader);
+        }
+        let informer_id = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        let devices_count = buf[4] as usize;
+        if buf.len() < WIDE_HEADER_SIZE + devices_count * WIDE_DEVICE_DATA_SIZE {
+            return Err(ProtocolError::TruncatedPayload);
+        }
+
+        let mut devices = Vec::new();
+        for i in 0..devices_count {
+            let offset = WIDE_HEADER_SIZE + i * WIDE_DEVICE_DATA_SIZE;
+            let device_data = &buf[offset..offset + WIDE_DEVICE_DATA_SIZE];
+            let sensor_id = u16::from_le_bytes(device_data[0..2].try_into().unwrap());
+            let address = DeviceAddress::Wide { informer_id, sensor_id };
+            let device_id = address.device_id();
+            devices.push(HardDevice {
+                id: device_id,
+                address,
+                name: format!("Device {}", device_id),
+                readings: vec![
+                    Reading::new(Channel::Temperature, f32::from_le_bytes(device_data[2..6].try_into().unwrap())),
+                    Reading::new(Channel::Humidity, f32::from_le_bytes(device_data[6..10].try_into().unwrap())),
+                ],
+                ..Default::default()
+            });
+        }
+        Ok(devices)
+    }
+
+    // [informer_id: u32] followed by [tag: u8][length: u8][value] records. A sensor
+    // record (value is sensor_id: u16) starts a device, channel records that
+    // follow it are its readings. Sequence (u32) and device time (u64, unix ms)
+    // records are optional.
+    // Batch frames carry history: after a sensor record every sample time record
+    // (u64, unix ms) starts one more sample of that sensor.
+    fn parse_tlv_telemetry(buf: &[u8], batch: bool) -> Result<Vec<HardDevice>, ProtocolError> {
+        if buf.len() < TLV_HEADER_SIZE {
+            return Err(ProtocolError::TruncatedHeader);
+        }
+        let informer_id = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+        let mut devices: Vec<HardDevice> = Vec::new();
+        let mut sensor: Option<DeviceAddress> = None;
+        let mut sample_started = false;
+        let mut sequence = None;
+        let mut device_time = None;
+        let mut offset = TLV_HEADER_SIZE;
+        while offset < buf.len() {
+            if offset + 2 > buf.len() {
+                return Err(ProtocolError::TruncatedPayload);
+            }
+            let tag = buf[offset];
+            let value_end = offset + 2 + buf[offset + 1] as usize;
+            if value_end > buf.len() {
+                return Err(ProtocolError::TruncatedPayload);
+            }
+            let value = &buf[offset + 2..value_end];
+            offset = value_end;
+
+            match (tag, value.len()) {
+                (TLV_TAG_SEQUENCE, 4) => {
+                    sequence = Some(u32::from_le_bytes(value.try_into().unwrap()));
+                    continue;
+                }
+                (TLV_TAG_DEVICE_TIME, 8) => {
+                    device_time = Some(u64::from_le_bytes(value.try_into().unwrap()));
+                    continue;
+                }
+                (TLV_TAG_SAMPLE_TIME, 8) if batch => {
+                    let address = sensor.ok_or(ProtocolError::BadRecord { tag })?;
+                    let mut sample = HardDevice::new_sensor(address);
+                    sample.device_time = Some(u64::from_le_bytes(value.try_into().unwrap()));
+                    sample.backfilled = true;
+                    devices.push(sample);
+                    sample_started = true;
+                    continue;
+                }
+                (TLV_TAG_SEQUENCE, _) | (TLV_TAG_DEVICE_TIME, _) | (TLV_TAG_SAMPLE_TIME, _) => {
+                    return Err(ProtocolError::BadRecord { tag })
+                }
+                _ => {}
+            }
+            if tag == TLV_TAG_SENSOR {
+                if value.len() != 2 {
+                    return Err(ProtocolError::BadRecord { tag });
+                }
+                let address = DeviceAddress::Wide { informer_id, sensor_id: u16::from_le_bytes([value[0], value[1]]) };
+                sensor = Some(address);
+                sample_started = !batch;
+                if batch {
+                    continue;
+                }
+                if devices.len() == MAX_DEVICES_PER_FRAME {
+                    return Err(ProtocolError::TooManyDevices);
+                }
+                devices.push(HardDevice::new_sensor(address));
+                continue;
+            }
+            let channel = Channel::from_tag(tag);
+            let reading = channel.decode_value(value).ok_or(ProtocolError::BadRecord { tag })?;
+            match devices.last_mut() {
+                Some(device) if sample_started => device.readings.push(Reading::new(channel, reading)),
+                _ => return Err(ProtocolError::BadRecord { tag }),
+            }
+        }
+        for device in devices.iter_mut() {
+            device.sequence = sequence;
+            if !batch {
+                device.device_time = device_time;
+            }
+        }
+        if batch {
+            devices.sort_by_key(|d| d.device_time);
+        }
+        Ok(devices)
+    }
+
+    fn new_sensor(address: DeviceAddress) -> HardDevice {
+        let device_id = address.device_id();
+        HardDevice {
+            id: device_id,
+            address,
+            name: format!("Device {}", device_id),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_device_id(device_id: u64) -> Option<HardDevice> {
+        DeviceAddress::from_device_id(device_id).map(HardDevice::new_sensor)
+    }
+
+    pub fn get_sequence(&self) -> Option<u32> {
+        self.sequence
+    }
+
+    pub fn get_device_time(&self) -> Option<u64> {
+        self.device_time
+    }
+
+    pub fn get_received_at(&self) -> Option<u64> {
+        self.received_at
+    }
+
+    pub fn is_backfilled(&self) -> bool {
+        self.backfilled
+    }
+
+    pub fn get_readings(&self) -> &[Reading] {
+        &self.readings
+    }
+
+    pub fn get_reading(&self, channel: Channel) -> Option<f32> {
+        self.readings.iter().find(|r| r.channel == channel).map(|r| r.value)
+    }
+
     pub fn get_temperature(&self) -> f32 {
-        self.temperature
+        self.get_reading(Channel::Temperature).unwrap_or_default()
     }
 
     pub fn get_humidity(&self) -> f32 {
-        self.humidity
+        self.get_reading(Channel::Humidity).unwrap_or_default()
+    }
+
+    pub fn get_target_temperature(&self) -> Option<f32> {
+        self.target_temperature
     }
 
-    pub fn get_id(&self) -> u32 {
+    pub fn get_target_humidity(&self) -> Option<f32> {
+        self.target_humidity
+    }
+
+    pub fn set_targets(&mut self, temperature: f32, humidity: f32) {
+        self.target_temperature = Some(temperature);
+        self.target_humidity = Some(humidity);
+    }
+
+    pub fn get_id(&self) -> u64 {
         self.id
     }
 
+    pub fn get_address(&self) -> DeviceAddress {
+        self.address
+    }
+
     pub fn get_id_str(&self) -> String {
         format!("{}", self.id)
     }
@@ -120,15 +449,56 @@ mod tests {
         let name = String::from("789");
         let temperature = 10.11;
         let device = HardDevice{
-            humidity,
             id,
             name,
-            temperature,
+            readings: vec![
+                Reading::new(Channel::Temperature, temperature),
+                Reading::new(Channel::Humidity, humidity),
+            ],
             ..Default::default()
         };
         assert_eq!(device.as_json(), format!("{{\"id\":{id}, \"name\":\"789\", \"temperature\":{temperature}, \"humidity\":{humidity}}}"))
     }
 
+    #[test]
+    fn as_json_timestamps() {
+        let device = HardDevice{
+            id: 5,
+            name: String::from("Device 5"),
+            readings: vec![Reading::new(Channel::Temperature, 4.5)],
+            sequence: Some(17),
+            device_time: Some(1700000000000),
+            received_at: Some(1700000000250),
+            ..Default::default()
+        };
+        assert_eq!(device.as_json(), "{\"id\":5, \"name\":\"Device 5\", \"temperature\":4.5, \"sequence\":17, \"device_time\":1700000000000, \"received_at\":1700000000250}")
+    }
+
+    #[test]
+    fn test_received_at() {
+        let before = chrono::Utc::now().timestamp_millis() as u64;
+        let devices = HardDevice::factory(&[1, 1, 0, 0, 0, 0, 0, 0, 0, 0], 10).unwrap();
+        let received_at = devices[0].get_received_at().unwrap();
+        assert!(received_at >= before);
+        assert_eq!(devices[0].get_sequence(), None);
+        assert_eq!(devices[0].get_device_time(), None);
+    }
+
+    #[test]
+    fn as_json_any_channels() {
+        let device = HardDevice{
+            id: 5,
+            name: String::from("Device 5"),
+            readings: vec![
+                Reading::new(Channel::DoorOpen, 1.0),
+                Reading::new(Channel::Rssi, -71.0),
+                Reading::new(Channel::Other(0x42), 2.5),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(device.as_json(), "{\"id\":5, \"name\":\"Device 5\", \"door_open\":1, \"rssi\":-71, \"channel_66\":2.5}")
+    }
+
     #[test]
     fn target_as_bytes() {
         let id = 456;
@@ -137,16 +507,15 @@ mod tests {
         let device = HardDevice{
             id: id,
             name: String::from("just-name"),
-            temperature: 0.0,
-            humidity: 0.0,
             target_humidity: Some(target_humidity),
             target_temperature: Some(target_temerature),
+            ..Default::default()
         };
-        let targets = device.target_as_bytes();
+        let targets = device.target_as_bytes().unwrap();
         let id_bytes = targets[0..4].try_into().unwrap();
         let humidity_bytes = targets[4..8].try_into().unwrap();
         let temperature_bytes = targets[8..12].try_into().unwrap();
-        assert_eq!(u32::from_le_bytes(id_bytes), id);
+        assert_eq!(u32::from_le_bytes(id_bytes) as u64, id);
         assert_eq!(f32::from_le_bytes(temperature_bytes), target_temerature);
         assert_eq!(f32::from_le_bytes(humidity_bytes), target_humidity);
     }
@@ -160,8 +529,6 @@ mod tests {
         let mut device = HardDevice{
             id: id,
             name: String::from("just-name"),
-            temperature: 0.0,
-            humidity: 0.0,
             ..Default::default()
         };
         
@@ -171,17 +538,27 @@ mod tests {
         };
 
         let config = serde_json::to_string(&mongo_config).unwrap();
-        device.set_config(&config);
-        let targets = device.target_as_bytes();
+        device.set_config(&config).unwrap();
+        let targets = device.target_as_bytes().unwrap();
         let id_bytes = targets[0..4].try_into().unwrap();
         let humidity_bytes = targets[4..8].try_into().unwrap();
         let temperature_bytes = targets[8..12].try_into().unwrap();
-        assert_eq!(u32::from_le_bytes(id_bytes), id);
+        assert_eq!(u32::from_le_bytes(id_bytes) as u64, id);
         assert_eq!(f32::from_le_bytes(temperature_bytes), target_temerature);
         assert_eq!(f32::from_le_bytes(humidity_bytes), target_humidity);
 
     }
 
+    #[test]
+    fn set_config_errors() {
+        let mut device = HardDevice::from_device_id(1201).unwrap();
+        assert_eq!(device.target_as_bytes(), Err(ConfigError::NoTargets { device_id: 1201 }));
+        for config in ["", "{\"temperature\": 4.5}", "{\"temperature\": \"4.5\", \"humidity\": 50}", "[]"] {
+            assert!(matches!(device.set_config(&config.to_owned()), Err(ConfigError::Malformed(_))), "{}", config);
+        }
+        assert_eq!(device.get_target_temperature(), None);
+    }
+
     #[test]
     fn test_single_init() {
         let devices = HardDevice::factory(&[1, 1, 0, 0, 0, 0, 0, 0, 0, 0], 10).unwrap();
@@ -242,15 +619,30 @@ mod tests {
     fn test_not_full_package() {
         let buf = [1,2,3,4];
         let devices = HardDevice::factory(&buf, buf.len());
-        assert!(devices.is_err());
-        assert_eq!(devices.err().unwrap().to_string(), "Not enough data to parse devices");
+        assert_eq!(devices.err(), Some(ProtocolError::TruncatedPayload));
     }
     #[test]
     fn test_not_enough_data_for_headers() {
         let buf = [1];
         let devices = HardDevice::factory(&buf, buf.len());
-        assert!(devices.is_err());
-        assert_eq!(devices.err().unwrap().to_string(), "Not enough data to parse header");
+        assert_eq!(devices.err(), Some(ProtocolError::TruncatedHeader));
+    }
+    #[test]
+    fn test_frame_length() {
+        assert_eq!(HardDevice::frame_length(&[]), None);
+        assert_eq!(HardDevice::frame_length(&[7]), None);
+        assert_eq!(HardDevice::frame_length(&[7, 0]), Some(2));
+        assert_eq!(HardDevice::frame_length(&[7, 2, 1, 2, 3]), Some(18));
+        assert_eq!(HardDevice::frame_length(&[7, 255]), Some(2042));
+    }
+    #[test]
+    fn test_more_than_127_devices() {
+        let mut buf = vec![0u8; 2 + 200 * 8];
+        buf[0] = 3;
+        buf[1] = 200;
+        let devices = HardDevice::factory(&buf, buf.len()).unwrap();
+        assert_eq!(devices.len(), 200);
+        assert_eq!(devices[199].get_id(), 500);
     }
 }
 
@@ -0,0 +1,114 @@
+// v1 informers have u8 ids and report sensors by position, so their devices got
+// `informer_id * 100 + position` ids and DeviceConfig rows are keyed by them.
+// Wide addresses keep resolving to the same rows while they fit that scheme and
+// map to a separate range above every legacy id otherwise.
+pub const LEGACY_SENSORS_LIMIT: u16 = 99;
+const WIDE_ID_SHIFT: u32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeviceAddress {
+    Legacy { informer_id: u8, index: u8 },
+    Wide { informer_id: u32, sensor_id: u16 },
+}
+
+impl Default for DeviceAddress {
+    fn default() -> Self {
+        DeviceAddress::Legacy { informer_id: 0, index: 0 }
+    }
+}
+
+impl DeviceAddress {
+    pub fn informer_id(&self) -> u32 {
+        match self {
+            DeviceAddress::Legacy { informer_id, .. } => *informer_id as u32,
+            DeviceAddress::Wide { informer_id, .. } => *informer_id,
+        }
+    }
+
+    pub fn device_id(&self) -> u64 {
+        match self {
+            DeviceAddress::Legacy { informer_id, index } => legacy_device_id(*informer_id, *index),
+            DeviceAddress::Wide { informer_id, sensor_id } => resolve_device_id(*informer_id, *sensor_id),
+        }
+    }
+
+    // Inverse of device_id for clients that only know the id, e.g. CoAP requests.
+    // Ids in the legacy range come back as the first 99 sensors of an informer.
+    pub fn from_device_id(device_id: u64) -> Option<DeviceAddress> {
+        let informer_id = device_id >> WIDE_ID_SHIFT;
+        if informer_id > u32::MAX as u64 + 1 {
+            return None;
+        }
+        if informer_id > 0 {
+            let sensor_id = (device_id & 0xFFFF) as u16;
+            return Some(DeviceAddress::Wide { informer_id: (informer_id - 1) as u32, sensor_id });
+        }
+        let sensor_id = (device_id % 100) as u16;
+        if device_id / 100 > u8::MAX as u64 || !(1..=LEGACY_SENSORS_LIMIT).contains(&sensor_id) {
+            return None;
+        }
+        Some(DeviceAddress::Legacy {
+            informer_id: (device_id / 100) as u8,
+            index: (sensor_id - 1) as u8,
+        })
+    }
+}
+
+pub fn legacy_device_id(informer_id: u8, index: u8) -> u64 {
+    informer_id as u64 * 100 + index as u64 + 1
+}
+
+pub fn resolve_device_id(informer_id: u32, sensor_id: u16) -> u64 {
+    if informer_id <= u8::MAX as u32 && (1..=LEGACY_SENSORS_LIMIT).contains(&sensor_id) {
+        return informer_id as u64 * 100 + sensor_id as u64;
+    }
+    ((informer_id as u64 + 1) << WIDE_ID_SHIFT) | sensor_id as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_legacy_ids() {
+        assert_eq!(legacy_device_id(12, 0), 1201);
+        assert_eq!(legacy_device_id(12, 1), 1202);
+        assert_eq!(DeviceAddress::Legacy { informer_id: 7, index: 1 }.device_id(), 702);
+    }
+
+    #[test]
+    fn test_wide_ids_resolve_to_legacy_rows() {
+        assert_eq!(resolve_device_id(12, 1), 1201);
+        assert_eq!(resolve_device_id(255, 99), 25599);
+        assert_eq!(
+            DeviceAddress::Wide { informer_id: 7, sensor_id: 2 }.device_id(),
+            DeviceAddress::Legacy { informer_id: 7, index: 1 }.device_id()
+        );
+    }
+
+    #[test]
+    fn test_wide_ids_out_of_legacy_range() {
+        let max_legacy_id = legacy_device_id(u8::MAX, u8::MAX);
+        for (informer_id, sensor_id) in [(12, 100), (12, 0), (256, 1), (u32::MAX, u16::MAX)] {
+            assert!(resolve_device_id(informer_id, sensor_id) > max_legacy_id);
+        }
+        assert_ne!(resolve_device_id(1, 100), resolve_device_id(2, 0));
+        assert_ne!(resolve_device_id(256, 1), resolve_device_id(257, 1));
+        assert_eq!(resolve_device_id(256, 1), (257 << 16) | 1);
+    }
+
+    #[test]
+    fn test_address_from_device_id() {
+        assert_eq!(DeviceAddress::from_device_id(1201), Some(DeviceAddress::Legacy { informer_id: 12, index: 0 }));
+        assert_eq!(DeviceAddress::from_device_id(1200), None);
+        assert_eq!(DeviceAddress::from_device_id(25600), None);
+        assert_eq!(DeviceAddress::from_device_id(u64::MAX), None);
+        for address in [
+            DeviceAddress::Legacy { informer_id: 255, index: 98 },
+            DeviceAddress::Wide { informer_id: 12, sensor_id: 100 },
+            DeviceAddress::Wide { informer_id: u32::MAX, sensor_id: 0 },
+        ] {
+            assert_eq!(DeviceAddress::from_device_id(address.device_id()), Some(address));
+        }
+    }
+}
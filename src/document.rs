@@ -0,0 +1,171 @@
+use serde_json::{json, Value};
+
+use crate::cbor;
+use crate::device::HardDevice;
+use crate::device_id::DeviceAddress;
+use crate::protocol::{ProtocolError, FRAME_TYPE_CBOR_DOCUMENT, FRAME_TYPE_CBOR_RESPONSE, FRAME_TYPE_JSON_DOCUMENT, FRAME_TYPE_JSON_RESPONSE};
+
+// Gateways may send telemetry as documents instead of packed readings:
+// {"informer": 12, "sequence": 5, "device_time": 1714000000000,
+//  "devices": [{"sensor": 1, "temperature": 4.5, "humidity": 51.0, "door_open": 0}]}
+// Readings are named the way as_json prints them, sequence and device_time are optional.
+// Documents are the payload of v2 frames, the frame type tells JSON from CBOR
+// and the response comes in a frame of the same format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContentFormat {
+    Json,
+    Cbor,
+}
+
+// Self-described CBOR tag, optional in requests
+pub const CBOR_MAGIC: [u8; 3] = [0xD9, 0xD9, 0xF7];
+
+impl ContentFormat {
+    pub fn from_frame_type(frame_type: u8) -> Option<ContentFormat> {
+        match frame_type {
+            FRAME_TYPE_JSON_DOCUMENT => Some(ContentFormat::Json),
+            FRAME_TYPE_CBOR_DOCUMENT => Some(ContentFormat::Cbor),
+            _ => None,
+        }
+    }
+
+    pub fn response_frame_type(&self) -> u8 {
+        match self {
+            ContentFormat::Json => FRAME_TYPE_JSON_RESPONSE,
+            ContentFormat::Cbor => FRAME_TYPE_CBOR_RESPONSE,
+        }
+    }
+}
+
+pub fn decode(buf: &[u8], format: ContentFormat) -> Result<Value, ProtocolError> {
+    match format {
+        ContentFormat::Json => serde_json::from_slice(buf).map_err(|_| ProtocolError::BadDocument),
+        ContentFormat::Cbor => cbor::decode(buf).map_err(|_| ProtocolError::BadDocument),
+    }
+}
+
+// CBOR responses are self-described
+pub fn encode(document: &Value, format: ContentFormat) -> Vec<u8> {
+    match format {
+        ContentFormat::Cbor => {
+            let mut buf = CBOR_MAGIC.to_vec();
+            buf.extend(cbor::encode(document));
+            buf
+        }
+        ContentFormat::Json => document.to_string().into_bytes(),
+    }
+}
+
+// Counterpart of ACK and NO_CONFIG frames in one document:
+// {"targets": [{"id": 1201, "sensor": 1, "temperature": 4.5, "humidity": 55.0}], "no_config": [1202]}
+pub fn response(configured: &[HardDevice], no_config: &[u64]) -> Value {
+    let targets: Vec<Value> = configured.iter().map(target).collect();
+    json!({ "targets": targets, "no_config": no_config })
+}
+
+// Counterpart of NACK frames, same error codes
+pub fn error_response(error: &ProtocolError) -> Value {
+    json!({ "error": error.to_string(), "code": error.code() })
+}
+
+fn target(hdevice: &HardDevice) -> Value {
+    let mut target = json!({
+        "id": hdevice.get_id(),
+        "temperature": hdevice.get_target_temperature(),
+        "humidity": hdevice.get_target_humidity(),
+    });
+    if let DeviceAddress::Wide { sensor_id, .. } = hdevice.get_address() {
+        target["sensor"] = json!(sensor_id);
+    }
+    target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use crate::reading::Channel;
+
+    const DOCUMENT: &str = r#"{"informer": 12, "sequence": 5, "devices": [
+        {"sensor": 1, "temperature": 4.5, "humidity": 51.0},
+        {"sensor": 300, "door_open": 1, "channel_66": -2}]}"#;
+
+    #[test]
+    fn test_frame_types() {
+        assert_eq!(ContentFormat::from_frame_type(FRAME_TYPE_JSON_DOCUMENT), Some(ContentFormat::Json));
+        assert_eq!(ContentFormat::from_frame_type(FRAME_TYPE_CBOR_DOCUMENT), Some(ContentFormat::Cbor));
+        assert_eq!(ContentFormat::from_frame_type(FRAME_TYPE_CBOR_RESPONSE), None);
+        assert_eq!(ContentFormat::Cbor.response_frame_type(), FRAME_TYPE_CBOR_RESPONSE);
+    }
+
+    #[test]
+    fn test_json_telemetry() {
+        let document = decode(DOCUMENT.as_bytes(), ContentFormat::Json).unwrap();
+        let devices = HardDevice::from_document(&document).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].get_id(), 1201);
+        assert_eq!(devices[0].get_temperature(), 4.5);
+        assert_eq!(devices[0].get_humidity(), 51.0);
+        assert_eq!(devices[0].get_sequence(), Some(5));
+        assert!(devices[0].get_received_at().is_some());
+        assert_eq!(devices[1].get_address(), DeviceAddress::Wide { informer_id: 12, sensor_id: 300 });
+        assert_eq!(devices[1].get_reading(Channel::DoorOpen), Some(1.0));
+        assert_eq!(devices[1].get_reading(Channel::Other(66)), Some(-2.0));
+    }
+
+    #[test]
+    fn test_cbor_telemetry() {
+        let json_document: Value = serde_json::from_str(DOCUMENT).unwrap();
+        let buf = encode(&json_document, ContentFormat::Cbor);
+        let document = decode(&buf, ContentFormat::Cbor).unwrap();
+        let devices = HardDevice::from_document(&document).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].get_id(), 1201);
+        assert_eq!(devices[0].get_temperature(), 4.5);
+        assert_eq!(devices[1].get_reading(Channel::Other(66)), Some(-2.0));
+    }
+
+    #[test]
+    fn test_bad_documents() {
+        assert_eq!(decode(b"{\"a\" 1}", ContentFormat::Json), Err(ProtocolError::BadDocument));
+        assert_eq!(decode(&[0xD9, 0xD9, 0xF7, 0xA1, 0x01, 0x01], ContentFormat::Cbor), Err(ProtocolError::BadDocument));
+        for document in [
+            r#"[]"#,
+            r#"{"devices": []}"#,
+            r#"{"informer": 4294967296, "devices": []}"#,
+            r#"{"informer": 12}"#,
+            r#"{"informer": 12, "devices": [{"temperature": 1.0}]}"#,
+            r#"{"informer": 12, "devices": [{"sensor": 1, "pressure": 1.0}]}"#,
+            r#"{"informer": 12, "devices": [{"sensor": 1, "temperature": "cold"}]}"#,
+            r#"{"informer": 12, "sequence": -1, "devices": []}"#,
+        ] {
+            let document: Value = serde_json::from_str(document).unwrap();
+            assert_eq!(HardDevice::from_document(&document).err(), Some(ProtocolError::BadDocument), "{}", document);
+        }
+    }
+
+    #[test]
+    fn test_responses() {
+        let document: Value = serde_json::from_str(DOCUMENT).unwrap();
+        let mut devices = HardDevice::from_document(&document).unwrap();
+        devices[0].set_config(&"{\"temperature\": 4.5, \"humidity\": 55.0}".to_owned()).unwrap();
+        let response = response(&devices[..1], &[devices[1].get_id()]);
+        assert_eq!(
+            response,
+            json!({
+                "targets": [{"id": 1201, "sensor": 1, "temperature": 4.5, "humidity": 55.0}],
+                "no_config": [devices[1].get_id()],
+            })
+        );
+        let buf = encode(&response, ContentFormat::Json);
+        assert_eq!(buf.last(), Some(&b'}'));
+        assert_eq!(decode(&buf, ContentFormat::Json), Ok(response.clone()));
+        let buf = encode(&response, ContentFormat::Cbor);
+        assert_eq!(decode(&buf, ContentFormat::Cbor), Ok(response));
+
+        assert_eq!(
+            error_response(&ProtocolError::BadDocument),
+            json!({"error": "Malformed telemetry document", "code": 9})
+        );
+    }
+}
@@ -1,11 +1,44 @@
 use async_trait::async_trait;
 
+use crate::command::{Command, CommandKind, CommandStatus};
+use crate::firmware::{FirmwareChunk, FirmwareOffer};
+
 #[async_trait]
 pub trait ChannelSender<D> {
     async fn send(&mut self, device: D) -> Result<(), std::io::Error>;
 }
 
 #[async_trait]
-pub trait ExternalDatabase {
-    async fn get_device_config(&mut self, key: &String) -> Result<String, std::io::Error>;
+pub trait ExternalDatabase: Send + Sync {
+    async fn get_device_config(&self, device_id: u64) -> Result<String, std::io::Error>;
+
+    // The `type` column of DeviceConfig, setpoint limits depend on it
+    async fn get_device_type(&self, _device_id: u64) -> Result<Option<String>, std::io::Error> {
+        Ok(None)
+    }
+
+    // Command queue, databases without one have nothing to deliver
+    async fn get_pending_commands(&self, _device_ids: &[u64]) -> Result<Vec<Command>, std::io::Error> {
+        Ok(Vec::new())
+    }
+    async fn get_commands(&self, _command_ids: &[u64]) -> Result<Vec<Command>, std::io::Error> {
+        Ok(Vec::new())
+    }
+    async fn set_command_status(&self, _command_ids: &[u64], _status: CommandStatus) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+    async fn enqueue_command(&self, _device_id: u64, _kind: CommandKind) -> Result<u64, std::io::Error> {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "No command queue"))
+    }
+
+    // Firmware by device type, None when the type has no image
+    async fn report_firmware_version(&self, _device_id: u64, _version: u32) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+    async fn get_firmware_offer(&self, _device_id: u64) -> Result<Option<FirmwareOffer>, std::io::Error> {
+        Ok(None)
+    }
+    async fn get_firmware_chunk(&self, _device_id: u64, _offset: u32, _length: u16) -> Result<Option<FirmwareChunk>, std::io::Error> {
+        Ok(None)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,124 @@
+use crate::protocol::ProtocolError;
+
+// Firmware updates are driven by informers, so a broken transfer resumes from
+// whatever offset the informer kept:
+// FIRMWARE_STATUS  [device_id: u64][running version: u32]
+//   answered with FIRMWARE_OFFER [version: u32][size: u32][sha256: 32] when the
+//   desired version for the device type differs, with an empty ACK otherwise
+// FIRMWARE_REQUEST [device_id: u64][version: u32][offset: u32][max length: u16]
+//   answered with FIRMWARE_CHUNK [version: u32][offset: u32][data]
+// The informer checks the SHA-256 of the whole image before installing it.
+pub const STATUS_SIZE: usize = 12;
+pub const REQUEST_SIZE: usize = 18;
+pub const MAX_CHUNK_SIZE: u16 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FirmwareStatus {
+    pub device_id: u64,
+    pub version: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkRequest {
+    pub device_id: u64,
+    pub version: u32,
+    pub offset: u32,
+    pub length: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirmwareOffer {
+    pub version: u32,
+    pub size: u32,
+    pub sha256: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirmwareChunk {
+    pub version: u32,
+    pub offset: u32,
+    pub data: Vec<u8>,
+}
+
+impl FirmwareStatus {
+    pub fn parse(payload: &[u8]) -> Result<FirmwareStatus, ProtocolError> {
+        if payload.len() != STATUS_SIZE {
+            return Err(ProtocolError::BadPayloadLength);
+        }
+        Ok(FirmwareStatus {
+            device_id: u64::from_le_bytes(payload[0..8].try_into().unwrap()),
+            version: u32::from_le_bytes(payload[8..12].try_into().unwrap()),
+        })
+    }
+}
+
+impl ChunkRequest {
+    // Length is capped, so chunks fit any informer buffer and a frame
+    pub fn parse(payload: &[u8]) -> Result<ChunkRequest, ProtocolError> {
+        if payload.len() != REQUEST_SIZE {
+            return Err(ProtocolError::BadPayloadLength);
+        }
+        Ok(ChunkRequest {
+            device_id: u64::from_le_bytes(payload[0..8].try_into().unwrap()),
+            version: u32::from_le_bytes(payload[8..12].try_into().unwrap()),
+            offset: u32::from_le_bytes(payload[12..16].try_into().unwrap()),
+            length: u16::from_le_bytes([payload[16], payload[17]]).min(MAX_CHUNK_SIZE),
+        })
+    }
+}
+
+impl FirmwareOffer {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = self.version.to_le_bytes().to_vec();
+        payload.extend_from_slice(&self.size.to_le_bytes());
+        payload.extend_from_slice(&self.sha256);
+        payload
+    }
+}
+
+impl FirmwareChunk {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = self.version.to_le_bytes().to_vec();
+        payload.extend_from_slice(&self.offset.to_le_bytes());
+        payload.extend_from_slice(&self.data);
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let mut payload = 1201u64.to_le_bytes().to_vec();
+        payload.extend_from_slice(&7u32.to_le_bytes());
+        assert_eq!(FirmwareStatus::parse(&payload), Ok(FirmwareStatus { device_id: 1201, version: 7 }));
+        assert_eq!(FirmwareStatus::parse(&payload[..11]), Err(ProtocolError::BadPayloadLength));
+    }
+
+    #[test]
+    fn test_chunk_request() {
+        let mut payload = 1201u64.to_le_bytes().to_vec();
+        payload.extend_from_slice(&8u32.to_le_bytes());
+        payload.extend_from_slice(&4096u32.to_le_bytes());
+        payload.extend_from_slice(&512u16.to_le_bytes());
+        let request = ChunkRequest::parse(&payload).unwrap();
+        assert_eq!(request, ChunkRequest { device_id: 1201, version: 8, offset: 4096, length: 512 });
+
+        payload[16..18].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(ChunkRequest::parse(&payload).unwrap().length, MAX_CHUNK_SIZE);
+        assert_eq!(ChunkRequest::parse(&payload[..17]), Err(ProtocolError::BadPayloadLength));
+    }
+
+    #[test]
+    fn test_encode() {
+        let offer = FirmwareOffer { version: 8, size: 70000, sha256: vec![0xAA; 32] };
+        let payload = offer.encode();
+        assert_eq!(payload.len(), 40);
+        assert_eq!(&payload[4..8], &70000u32.to_le_bytes());
+
+        let chunk = FirmwareChunk { version: 8, offset: 1024, data: vec![1, 2, 3] };
+        assert_eq!(chunk.encode(), vec![8, 0, 0, 0, 0, 4, 0, 0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,129 @@
+use crate::protocol;
+
+// Accumulates bytes read from an informer connection and cuts them into
+// complete frames, no matter how the stream was split into segments.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder {
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        let frame_length = protocol::frame_length(&self.buffer)?;
+        if self.buffer.len() < frame_length {
+            return None;
+        }
+        Some(self.buffer.drain(..frame_length).collect())
+    }
+
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameDecoder;
+    use crate::device::HardDevice;
+    use crate::protocol::{self, FRAME_TYPE_TELEMETRY};
+
+    fn make_frame(informer_id: u8, devices_count: u8) -> Vec<u8> {
+        let mut frame = vec![informer_id, devices_count];
+        for i in 0..devices_count {
+            frame.extend_from_slice(&f32::to_le_bytes(i as f32));
+            frame.extend_from_slice(&f32::to_le_bytes(i as f32 / 10.0));
+        }
+        frame
+    }
+
+    #[test]
+    fn test_single_frame() {
+        let frame = make_frame(1, 2);
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&frame);
+        assert_eq!(decoder.next_frame(), Some(frame));
+        assert_eq!(decoder.next_frame(), None);
+        assert_eq!(decoder.pending(), 0);
+    }
+
+    #[test]
+    fn test_split_frame() {
+        let frame = make_frame(1, 2);
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&frame[..1]);
+        assert_eq!(decoder.next_frame(), None, "Header is not complete");
+        decoder.push(&frame[1..7]);
+        assert_eq!(decoder.next_frame(), None, "Payload is not complete");
+        decoder.push(&frame[7..]);
+        assert_eq!(decoder.next_frame(), Some(frame));
+    }
+
+    #[test]
+    fn test_coalesced_frames() {
+        let first = make_frame(1, 2);
+        let second = make_frame(2, 1);
+        let mut data = first.clone();
+        data.extend_from_slice(&second);
+        data.extend_from_slice(&[3]);
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&data);
+        assert_eq!(decoder.next_frame(), Some(first));
+        assert_eq!(decoder.next_frame(), Some(second));
+        assert_eq!(decoder.next_frame(), None);
+        assert_eq!(decoder.pending(), 1, "Start of the third frame stays buffered");
+    }
+
+    #[test]
+    fn test_frame_bigger_than_read_buffer() {
+        let frame = make_frame(4, 200);
+        let mut decoder = FrameDecoder::new();
+        for chunk in frame.chunks(1024) {
+            decoder.push(chunk);
+        }
+        let decoded = decoder.next_frame().unwrap();
+        let devices = HardDevice::factory(&decoded, decoded.len()).unwrap();
+        assert_eq!(devices.len(), 200);
+    }
+
+    #[test]
+    fn test_split_v2_frame() {
+        let frame = protocol::encode(FRAME_TYPE_TELEMETRY, &make_frame(1, 3));
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&frame[..4]);
+        assert_eq!(decoder.next_frame(), None, "v2 header is not complete");
+        decoder.push(&frame[4..frame.len() - 1]);
+        assert_eq!(decoder.next_frame(), None, "Checksum is not complete");
+        decoder.push(&frame[frame.len() - 1..]);
+        assert_eq!(decoder.next_frame(), Some(frame));
+    }
+
+    #[test]
+    fn test_frames_starting_like_documents() {
+        // Informer 0x7B with 34 devices starts with `{"`, it is still a v1 frame
+        let frame = make_frame(0x7B, 34);
+        assert!(frame.starts_with(b"{\""));
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&frame);
+        decoder.push(&make_frame(1, 1));
+        assert_eq!(decoder.next_frame(), Some(frame));
+        assert_eq!(decoder.next_frame(), Some(make_frame(1, 1)));
+    }
+
+    #[test]
+    fn test_empty_frame() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&[5, 0]);
+        assert_eq!(decoder.next_frame(), Some(vec![5, 0]));
+    }
+}
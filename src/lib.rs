@@ -1,5 +1,19 @@
 pub mod external;
 pub mod service_server;
 pub mod device;
+pub mod device_id;
 pub mod sqlconnector;
-mod datacache;
\ No newline at end of file
+pub mod framing;
+pub mod protocol;
+pub mod reading;
+pub mod sequence;
+pub mod coap;
+pub mod cbor;
+pub mod document;
+pub mod tls;
+pub mod auth;
+pub mod command;
+pub mod firmware;
+pub mod setpoint;
+pub mod target_store;
+pub mod datacache;
\ No newline at end of file
@@ -1,20 +1,25 @@
+mod coap_server;
 mod config;
 mod metrics;
 mod mqtt_sender;
+mod session;
 mod state;
+mod udp_listener;
 use async_std::io as aio;
+use async_std::task;
 use async_std::task::block_on;
-use be_server::device::Device;
-use be_server::device::HardDevice;
+use be_server::auth::{CounterStore, KeyStore};
+use be_server::datacache::Datacache;
 use be_server::external::abstract_external::ExternalDatabase;
 use be_server::service_server::ServiceServer;
+use be_server::setpoint::{SetpointEvent, SetpointGuard};
 use be_server::sqlconnector::PostgressDatabase;
+use be_server::target_store::TargetStore;
+use be_server::tls;
 use clap::Parser;
 use log::error;
 use mqtt_sender::MqttSender;
 use state::GlobalState;
-use tokio::io::AsyncWriteExt;
-use std::error::Error;
 use std::io;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicUsize;
@@ -23,56 +28,18 @@ use std::thread;
 use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
 use be_server::device;
 
-async fn process_socket(mut socket: TcpStream, state: &GlobalState, external_database: &mut dyn ExternalDatabase) -> Result<(), Box<dyn Error>> {
-    let mut buf = [0; 1024];
-    socket.readable().await?;
-
-    match socket.try_read(&mut buf) {
-        Ok(0) => return Ok(()),
-        Ok(n) => {
-            println!("read {} bytes", n);
-            let devices = device::HardDevice::factory(&buf, n);
-            match devices {
-                Ok(devices) => {
-                    for mut hdevice in devices {
-                        let dev_json = hdevice.as_json();
-                        let device_id = hdevice.get_id_str();
-                        state.new_device(hdevice.clone());
-                        println!("{}", dev_json);
-                        match external_database.get_device_config(&device_id).await {
-                            Ok(config_str) => {
-                                hdevice.set_config(&config_str);
-                                socket.write(hdevice.target_as_bytes().as_slice()).await?;
-                            },
-                            Err(_) => {
-                                
-                            }   
-                        }
-
-
-                    }
-                }
-                Err(e) => {
-                    return Err(e.into());
-                }
-            }
-        }
-        Err(e) => {
-            return Err(e.into());
-        }
-    }
-    Ok(())
-}
-
 async fn listener_routine(
     process_running: Arc<AtomicBool>,
     addr: String,
     state: GlobalState,
     service_counter: Arc<AtomicUsize>,
-    external_database: &mut dyn ExternalDatabase
+    external_database: Arc<dyn ExternalDatabase>,
+    tls_acceptor: Option<TlsAcceptor>,
 ) -> io::Result<()> {
+    let connections_limit = state.get_connections_limit();
     println!("Start listen on: {}", addr);
     let listener = TcpListener::bind(addr).await?;
     println!("Listener started");
@@ -91,12 +58,29 @@ async fn listener_routine(
         }
         let socket: TcpStream = socket.unwrap();
 
-        match process_socket(socket, &state, external_database).await {
-            Ok(_) => {}
-            Err(e) => {
-                error!("Error: {}", e);
+        let permit = match connections_limit.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                error!("Connections limit {} is reached, drop connection", state.get_max_connections());
+                continue;
             }
-        }
+        };
+        let connection_state = state.clone();
+        let connection_database = external_database.clone();
+        let connection_acceptor = tls_acceptor.clone();
+        task::spawn(async move {
+            let result = match connection_acceptor {
+                Some(acceptor) => session::process_tls_socket(acceptor, socket, &connection_state, connection_database.as_ref()).await,
+                None => session::process_socket(socket, None, &connection_state, connection_database.as_ref()).await,
+            };
+            match result {
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Error: {}", e);
+                }
+            }
+            drop(permit);
+        });
     }
 }
 
@@ -105,10 +89,39 @@ async fn main() -> io::Result<()> {
     println!("HERE");
     let (metrics_snd_channel, metrics_rcv_channel) = std::sync::mpsc::channel::<device::HardDevice>();
     let state = state::GlobalState::new(metrics_snd_channel, config::ServerConfig::parse());
+    let state = match (state.get_psk_file(), state.get_counter_file()) {
+        (Some(psk_file), Some(counter_file)) => {
+            let keys = KeyStore::load(&psk_file)?;
+            println!("Loaded pre-shared keys of {} informers", keys.len());
+            let counters = CounterStore::open(&counter_file)?;
+            println!("Loaded last frame counters of {} informers", counters.len());
+            state.with_keys(keys, counters)
+        }
+        _ => state,
+    };
+    let state = match state.get_limits_file() {
+        Some(limits_file) => {
+            let setpoint_guard = SetpointGuard::load(&limits_file)?;
+            println!("Loaded setpoint limits of {} device types", setpoint_guard.len());
+            state.with_setpoint_limits(setpoint_guard)
+        }
+        None => state,
+    };
+    let state = match state.get_targets_file() {
+        Some(targets_file) => {
+            let target_store = TargetStore::open(&targets_file)?;
+            println!("Loaded last targets of {} devices", target_store.len());
+            state.with_target_store(target_store)
+        }
+        None => state,
+    };
+    let (events_snd_channel, events_rcv_channel) = std::sync::mpsc::channel::<SetpointEvent>();
+    let state = state.with_events(events_snd_channel);
     let service_counter = Arc::new(AtomicUsize::new(0));
 
     println!("Starting BE Server");
     let channel_sender = MqttSender::new(state.get_mqtt_config());
+    let events_channel_sender = channel_sender.clone();
 
     let programm_is_run = Arc::new(AtomicBool::new(true));
     let programm_is_run_metrics_copy = programm_is_run.clone();
@@ -117,24 +130,90 @@ async fn main() -> io::Result<()> {
     service_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     let metrics_service_counter = service_counter.clone();
     let _metrics_thread = thread::spawn(move || {
-        let mut metrics = metrics::Metrics::<MqttSender>::new(metrics_rcv_channel, channel_sender);
+        let mut metrics = metrics::Metrics::<device::HardDevice, MqttSender>::new(metrics_rcv_channel, channel_sender);
         metrics.run(programm_is_run_metrics_copy, metrics_service_counter);
     });
 
+    service_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    let events_service_counter = service_counter.clone();
+    let programm_is_run_events_copy = programm_is_run.clone();
+    thread::spawn(move || {
+        let mut events = metrics::Metrics::<SetpointEvent, MqttSender>::new(events_rcv_channel, events_channel_sender);
+        events.run(programm_is_run_events_copy, events_service_counter);
+    });
+
     let programm_is_run_listener_copy = programm_is_run.clone();
     let listener_state_clone = state.clone();
     println!("Init Listener thread");
 
-    let mut postgres_client = PostgressDatabase::new(
+    let postgres_database = Arc::new(PostgressDatabase::new(
         state.get_sql_login(),
         state.get_sql_password(),
         state.get_sql_host(),
         state.get_sql_dbname(),
         state.get_sql_port(),
-    );
+    ));
+    if !postgres_database.is_connected() {
+        let reconnect_database = postgres_database.clone();
+        let programm_is_run_reconnect_copy = programm_is_run.clone();
+        let reconnect_interval = state.get_reconnect_interval();
+        thread::spawn(move || {
+            println!("Reconnecting to database every {:?}", reconnect_interval);
+            reconnect_database.reconnect_routine(programm_is_run_reconnect_copy, reconnect_interval);
+        });
+    }
+    let postgres_client: Arc<dyn ExternalDatabase> = postgres_database.clone();
+    let mut config_cache = Datacache::new(postgres_client.clone());
+    config_cache
+        .set_capacity(state.get_cache_capacity())
+        .set_experity_time(state.get_cache_ttl())
+        .set_grace_time(state.get_cache_grace())
+        .set_negative_time(state.get_cache_negative_ttl());
+    let config_cache = Arc::new(config_cache);
+
+    let notified_cache = config_cache.clone();
+    let programm_is_run_notify_copy = programm_is_run.clone();
+    let notify_interval = state.get_reconnect_interval();
+    thread::spawn(move || {
+        postgres_database.config_changes_routine(
+            programm_is_run_notify_copy,
+            notify_interval,
+            |device_id| {
+                notified_cache.invalidate(device_id);
+            },
+            || {
+                notified_cache.invalidate_all();
+            },
+        );
+    });
+
+    let target_store = state.get_target_store();
+    let programm_is_run_targets_copy = programm_is_run.clone();
+    let _targets_thread = thread::spawn(move || {
+        while programm_is_run_targets_copy.load(std::sync::atomic::Ordering::Relaxed) {
+            thread::sleep(Duration::from_secs(1));
+            if let Err(e) = target_store.flush() {
+                error!("Can't save last targets: {}", e);
+            }
+        }
+    });
+
+    let tls_acceptor = match state.get_tls_config() {
+        Some(tls_config) => {
+            println!("Listener uses TLS");
+            let server_config = tls::server_config(
+                &tls_config.cert_path,
+                &tls_config.key_path,
+                tls_config.client_ca_path.as_deref(),
+            )?;
+            Some(TlsAcceptor::from(server_config))
+        }
+        None => None,
+    };
 
     service_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     let listener_service_counter = service_counter.clone();
+    let listener_database = config_cache.clone();
     let _listener_thread = thread::spawn(move || {
         println!("Start Listener...");
         let _ = block_on(listener_routine(
@@ -142,14 +221,63 @@ async fn main() -> io::Result<()> {
             listener_state_clone.get_tcp_addr(),
             listener_state_clone,
             listener_service_counter,
-            &mut postgres_client as &mut dyn ExternalDatabase,
+            listener_database,
+            tls_acceptor,
         ));
     });
 
+    match state.get_udp_addr() {
+        Some(udp_addr) => {
+            service_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let udp_service_counter = service_counter.clone();
+            let programm_is_run_udp_copy = programm_is_run.clone();
+            let udp_state_clone = state.clone();
+            let udp_database = config_cache.clone();
+            thread::spawn(move || {
+                println!("Start UDP Listener...");
+                let _ = block_on(udp_listener::udp_listener_routine(
+                    programm_is_run_udp_copy,
+                    udp_addr,
+                    udp_state_clone,
+                    udp_service_counter,
+                    udp_database,
+                ));
+            });
+        }
+        None => {}
+    }
+
+    match state.get_coap_addr() {
+        Some(coap_addr) => {
+            service_counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let coap_service_counter = service_counter.clone();
+            let programm_is_run_coap_copy = programm_is_run.clone();
+            let coap_state_clone = state.clone();
+            let coap_database = config_cache.clone();
+            thread::spawn(move || {
+                println!("Start CoAP Listener...");
+                let _ = block_on(coap_server::coap_listener_routine(
+                    programm_is_run_coap_copy,
+                    coap_addr,
+                    coap_state_clone,
+                    coap_service_counter,
+                    coap_database,
+                ));
+            });
+        }
+        None => {}
+    }
+
     match state.get_service_port() {
         Some(port) => {
+            let service_database = postgres_client.clone();
+            let service_target_store = state.get_target_store();
+            let service_config_cache = config_cache.clone();
             thread::spawn(move || {
-                let service_server = ServiceServer::new(service_counter, port);
+                let service_server = ServiceServer::new(service_counter, port)
+                    .with_external_database(service_database)
+                    .with_target_store(service_target_store)
+                    .with_config_cache(service_config_cache);
                 service_server.run_listener();
             });
         }
@@ -162,5 +290,6 @@ async fn main() -> io::Result<()> {
     });
     let _ = _listener_thread.join().expect("Can't join listener thread");
     _metrics_thread.join().expect("Can't join metrics thread");
+    _targets_thread.join().expect("Can't join targets thread");
     Ok(())
 }
@@ -1,19 +1,19 @@
-use crate::device::Device;
 use async_std::task::block_on;
-use be_server::device::HardDevice;
+use log::debug;
 use be_server::external::abstract_external;
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, atomic::{AtomicBool, Ordering::Relaxed, AtomicUsize}};
 use std::time::Duration;
 
-pub struct Metrics<T: abstract_external::ChannelSender<HardDevice>> {
-    reciever_channel: Receiver<HardDevice>,
+// Forwards device readings or setpoint events from the channel to the sender
+pub struct Metrics<D, T: abstract_external::ChannelSender<D>> {
+    reciever_channel: Receiver<D>,
     channel_sender: T,
 
 }
 
-impl<'a, T: abstract_external::ChannelSender<HardDevice>> Metrics<T>{
-    pub fn new(rec: Receiver<HardDevice>, sender: T) -> Metrics<T> {
+impl<'a, D, T: abstract_external::ChannelSender<D>> Metrics<D, T>{
+    pub fn new(rec: Receiver<D>, sender: T) -> Metrics<D, T> {
         Metrics {
             reciever_channel: rec,
             channel_sender: sender,
@@ -23,9 +23,9 @@ impl<'a, T: abstract_external::ChannelSender<HardDevice>> Metrics<T>{
         service_counter.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
         while run.load(Relaxed) {
             match self.reciever_channel.recv_timeout(Duration::from_millis(20)) {
-                Ok(device) => {
-                    println!("New device in channel: {}", device.get_id());
-                    block_on(self.channel_sender.send(device)).unwrap();
+                Ok(message) => {
+                    debug!("New message in channel");
+                    block_on(self.channel_sender.send(message)).unwrap();
                 }
                 Err(_) => {
                     continue;
@@ -69,7 +69,7 @@ mod tests {
         let (snd, rcv) = channel::<HardDevice>();
         let (test_snd, test_rcv) = channel::<HardDevice>();
         let mock = MockChannelSender::new(test_snd);
-        let mut metrics = super::Metrics::<MockChannelSender>::new(rcv, mock);
+        let mut metrics = super::Metrics::<HardDevice, MockChannelSender>::new(rcv, mock);
         
         //TODO: Make factory for bytes to make device
         let mut buf = [0; 1024];
@@ -1,6 +1,6 @@
 use std::io::Error;
 
-use be_server::{device::HardDevice, external::abstract_external::ChannelSender};
+use be_server::{device::HardDevice, external::abstract_external::ChannelSender, setpoint::SetpointEvent};
 use async_trait::async_trait;
 use futures::executor::block_on;
 
@@ -8,6 +8,7 @@ extern crate paho_mqtt as mqtt;
 
 use crate::{device::Device, state::MqttConfig};
 
+#[derive(Clone)]
 pub struct MqttSender {
     topic: String,
     client: mqtt::AsyncClient
@@ -45,3 +46,17 @@ impl ChannelSender<HardDevice> for MqttSender {
         
     }
 }
+
+// Setpoint events go to a subtopic, next to the readings
+#[async_trait]
+impl ChannelSender<SetpointEvent> for MqttSender {
+    async fn send(&mut self, event: SetpointEvent) -> Result<(), Error> {
+        let msg = mqtt::MessageBuilder::new()
+            .topic(format!("{}/events", self.topic))
+            .payload(event.as_json())
+            .qos(1)
+            .finalize();
+        self.client.publish(msg).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,564 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::command::Command;
+use crate::device::HardDevice;
+use crate::firmware::{FirmwareChunk, FirmwareOffer};
+
+// v2 frame layout, all numbers are little-endian:
+// [magic: 2][version: u8][frame type: u8][payload length: u16][payload][crc32: u32]
+// The checksum covers everything between the magic and the checksum itself.
+// v1 frames have no header at all and are detected by the absence of the magic,
+// so a legacy informer 0xBE reporting 0xEF devices can't be told apart from v2.
+pub const MAGIC: [u8; 2] = [0xBE, 0xEF];
+pub const VERSION_2: u8 = 2;
+pub const V2_HEADER_SIZE: usize = 6;
+pub const CHECKSUM_SIZE: usize = 4;
+
+pub const FRAME_TYPE_TELEMETRY: u8 = 0x01;
+pub const FRAME_TYPE_TELEMETRY_WIDE: u8 = 0x02;
+pub const FRAME_TYPE_TELEMETRY_TLV: u8 = 0x03;
+pub const FRAME_TYPE_TELEMETRY_BATCH: u8 = 0x04;
+// Informer confirms it executed commands, see command.rs
+pub const FRAME_TYPE_COMMAND_ACK: u8 = 0x05;
+// Firmware update requests, see firmware.rs
+pub const FRAME_TYPE_FIRMWARE_STATUS: u8 = 0x06;
+pub const FRAME_TYPE_FIRMWARE_REQUEST: u8 = 0x07;
+// Telemetry documents of gateways, see document.rs
+pub const FRAME_TYPE_JSON_DOCUMENT: u8 = 0x08;
+pub const FRAME_TYPE_CBOR_DOCUMENT: u8 = 0x09;
+// HMAC-SHA256 signed wrapper of any frame above, see auth.rs
+pub const FRAME_TYPE_AUTHENTICATED: u8 = 0x10;
+
+// Responses to v2 frames, server to informer
+pub const FRAME_TYPE_ACK: u8 = 0x81;
+pub const FRAME_TYPE_NACK: u8 = 0x82;
+pub const FRAME_TYPE_NO_CONFIG: u8 = 0x83;
+pub const FRAME_TYPE_COMMAND: u8 = 0x84;
+pub const FRAME_TYPE_FIRMWARE_OFFER: u8 = 0x85;
+pub const FRAME_TYPE_FIRMWARE_CHUNK: u8 = 0x86;
+// Response document in the format of the request
+pub const FRAME_TYPE_JSON_RESPONSE: u8 = 0x88;
+pub const FRAME_TYPE_CBOR_RESPONSE: u8 = 0x89;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolError {
+    TruncatedHeader,
+    TruncatedPayload,
+    // Complete frame declares more devices than its payload carries
+    TooManyDevices,
+    BadChecksum,
+    UnsupportedVersion(u8),
+    UnsupportedFrameType(u8),
+    NonFiniteReading { device_id: u64 },
+    BadRecord { tag: u8 },
+    BadDocument,
+    // Authenticated connection reports for an informer its certificate doesn't name
+    ForeignInformer { informer_id: u32 },
+    // Informer has a pre-shared key but the frame isn't signed with it, or the other way round
+    Unauthenticated { informer_id: u32 },
+    BadTag { informer_id: u32 },
+    StaleCounter { informer_id: u32 },
+    // Last counter couldn't be stored, the frame would be replayable after a restart
+    CounterNotSaved { informer_id: u32 },
+    // No image for the device or the desired version changed during the transfer
+    FirmwareUnavailable,
+    // Payload of a fixed layout frame has another size than its type defines
+    BadPayloadLength,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::TruncatedHeader => write!(f, "Not enough data to parse header"),
+            ProtocolError::TruncatedPayload => write!(f, "Not enough data to parse devices"),
+            ProtocolError::TooManyDevices => write!(f, "Devices count doesn't fit the frame"),
+            ProtocolError::BadChecksum => write!(f, "Bad frame checksum"),
+            ProtocolError::UnsupportedVersion(version) => write!(f, "Unsupported protocol version {}", version),
+            ProtocolError::UnsupportedFrameType(frame_type) => write!(f, "Unsupported frame type {}", frame_type),
+            ProtocolError::NonFiniteReading { device_id } => write!(f, "Non-finite reading from device {}", device_id),
+            ProtocolError::BadRecord { tag } => write!(f, "Malformed record with tag {}", tag),
+            ProtocolError::BadDocument => write!(f, "Malformed telemetry document"),
+            ProtocolError::ForeignInformer { informer_id } => write!(f, "Connection may not report for informer {}", informer_id),
+            ProtocolError::Unauthenticated { informer_id } => write!(f, "Informer {} frame is not authenticated", informer_id),
+            ProtocolError::BadTag { informer_id } => write!(f, "Bad authentication tag of informer {}", informer_id),
+            ProtocolError::StaleCounter { informer_id } => write!(f, "Outdated frame counter of informer {}", informer_id),
+            ProtocolError::CounterNotSaved { informer_id } => write!(f, "Can't save frame counter of informer {}", informer_id),
+            ProtocolError::FirmwareUnavailable => write!(f, "Requested firmware is not available"),
+            ProtocolError::BadPayloadLength => write!(f, "Payload length doesn't fit the frame type"),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl ProtocolError {
+    pub fn code(&self) -> u8 {
+        match self {
+            ProtocolError::TruncatedHeader => 1,
+            ProtocolError::TruncatedPayload => 2,
+            ProtocolError::TooManyDevices => 3,
+            ProtocolError::BadChecksum => 4,
+            ProtocolError::UnsupportedVersion(_) => 5,
+            ProtocolError::UnsupportedFrameType(_) => 6,
+            ProtocolError::NonFiniteReading { .. } => 7,
+            ProtocolError::BadRecord { .. } => 8,
+            ProtocolError::BadDocument => 9,
+            ProtocolError::ForeignInformer { .. } => 10,
+            ProtocolError::Unauthenticated { .. } => 11,
+            ProtocolError::BadTag { .. } => 12,
+            ProtocolError::StaleCounter { .. } => 13,
+            ProtocolError::FirmwareUnavailable => 14,
+            ProtocolError::BadPayloadLength => 15,
+            ProtocolError::CounterNotSaved { .. } => 16,
+        }
+    }
+
+    // Payload of a v2 frame is complete by construction, running out of it means a wrong count
+    pub fn within_frame(self) -> ProtocolError {
+        match self {
+            ProtocolError::TruncatedPayload => ProtocolError::TooManyDevices,
+            e => e,
+        }
+    }
+}
+
+pub enum Frame<'a> {
+    Legacy(&'a [u8]),
+    V2 { frame_type: u8, payload: &'a [u8] },
+}
+
+// ACK:       [count: u8][target bytes of every device with config]
+// NACK:      [error code: u8]
+// NO_CONFIG: [count: u8][(device id: u64) * count]
+// COMMAND:   one queued command, see command.rs
+// FIRMWARE_OFFER and FIRMWARE_CHUNK: see firmware.rs
+pub enum Response {
+    Ack(Vec<Vec<u8>>),
+    Nack(ProtocolError),
+    NoConfig(Vec<u64>),
+    Command(Command),
+    FirmwareOffer(FirmwareOffer),
+    FirmwareChunk(FirmwareChunk),
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            Response::Ack(targets) => {
+                payload.push(targets.len() as u8);
+                targets.iter().for_each(|target| payload.extend_from_slice(target));
+                encode(FRAME_TYPE_ACK, &payload)
+            }
+            Response::Nack(error) => {
+                payload.push(error.code());
+                encode(FRAME_TYPE_NACK, &payload)
+            }
+            Response::NoConfig(device_ids) => {
+                payload.push(device_ids.len() as u8);
+                device_ids.iter().for_each(|id| payload.extend_from_slice(&id.to_le_bytes()));
+                encode(FRAME_TYPE_NO_CONFIG, &payload)
+            }
+            Response::Command(command) => encode(FRAME_TYPE_COMMAND, &command.encode()),
+            Response::FirmwareOffer(offer) => encode(FRAME_TYPE_FIRMWARE_OFFER, &offer.encode()),
+            Response::FirmwareChunk(chunk) => encode(FRAME_TYPE_FIRMWARE_CHUNK, &chunk.encode()),
+        }
+    }
+}
+
+pub fn is_v2(buf: &[u8]) -> bool {
+    buf.len() >= MAGIC.len() && buf[..MAGIC.len()] == MAGIC
+}
+
+// Full size of the frame starting at buf[0], or None while the header is incomplete
+pub fn frame_length(buf: &[u8]) -> Option<usize> {
+    if !is_v2(buf) {
+        return HardDevice::frame_length(buf);
+    }
+    if buf.len() < V2_HEADER_SIZE {
+        return None;
+    }
+    let payload_length = u16::from_le_bytes([buf[4], buf[5]]) as usize;
+    Some(V2_HEADER_SIZE + payload_length + CHECKSUM_SIZE)
+}
+
+pub fn decode(buf: &[u8]) -> Result<Frame<'_>, ProtocolError> {
+    if !is_v2(buf) {
+        return Ok(Frame::Legacy(buf));
+    }
+    if buf.len() < V2_HEADER_SIZE {
+        return Err(ProtocolError::TruncatedHeader);
+    }
+    let version = buf[2];
+    if version != VERSION_2 {
+        return Err(ProtocolError::UnsupportedVersion(version));
+    }
+    let frame_type = buf[3];
+    let payload_end = V2_HEADER_SIZE + u16::from_le_bytes([buf[4], buf[5]]) as usize;
+    if buf.len() < payload_end + CHECKSUM_SIZE {
+        return Err(ProtocolError::TruncatedPayload);
+    }
+    let checksum = u32::from_le_bytes(buf[payload_end..payload_end + CHECKSUM_SIZE].try_into().unwrap());
+    if checksum != crc32fast::hash(&buf[MAGIC.len()..payload_end]) {
+        return Err(ProtocolError::BadChecksum);
+    }
+    Ok(Frame::V2 {
+        frame_type,
+        payload: &buf[V2_HEADER_SIZE..payload_end],
+    })
+}
+
+pub fn encode(frame_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(V2_HEADER_SIZE + payload.len() + CHECKSUM_SIZE);
+    frame.extend_from_slice(&MAGIC);
+    frame.push(VERSION_2);
+    frame.push(frame_type);
+    frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    frame.extend_from_slice(payload);
+    let checksum = crc32fast::hash(&frame[MAGIC.len()..]);
+    frame.extend_from_slice(&checksum.to_le_bytes());
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use crate::device_id::DeviceAddress;
+    use crate::reading::Channel;
+
+    fn telemetry_payload() -> Vec<u8> {
+        let mut payload = vec![7, 2];
+        payload.extend_from_slice(&f32::to_le_bytes(0.123));
+        payload.extend_from_slice(&f32::to_le_bytes(0.456));
+        payload.extend_from_slice(&f32::to_le_bytes(1.5));
+        payload.extend_from_slice(&f32::to_le_bytes(2.5));
+        payload
+    }
+
+    #[test]
+    fn test_frame_length() {
+        let frame = encode(FRAME_TYPE_TELEMETRY, &telemetry_payload());
+        assert_eq!(frame_length(&frame[..5]), None);
+        assert_eq!(frame_length(&frame[..6]), Some(frame.len()));
+        assert_eq!(frame_length(&[7, 2]), Some(18), "Legacy frame length");
+    }
+
+    #[test]
+    fn test_v2_telemetry() {
+        let frame = encode(FRAME_TYPE_TELEMETRY, &telemetry_payload());
+        let devices = HardDevice::factory(&frame, frame.len()).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].get_id(), 701);
+        assert_eq!(devices[0].get_temperature(), 0.123);
+        assert_eq!(devices[1].get_id(), 702);
+        assert_eq!(devices[1].get_humidity(), 2.5);
+    }
+
+    fn wide_telemetry_payload(informer_id: u32, sensor_ids: &[u16]) -> Vec<u8> {
+        let mut payload = informer_id.to_le_bytes().to_vec();
+        payload.push(sensor_ids.len() as u8);
+        for sensor_id in sensor_ids {
+            payload.extend_from_slice(&sensor_id.to_le_bytes());
+            payload.extend_from_slice(&f32::to_le_bytes(*sensor_id as f32));
+            payload.extend_from_slice(&f32::to_le_bytes(0.5));
+        }
+        payload
+    }
+
+    #[test]
+    fn test_v2_wide_telemetry() {
+        let frame = encode(FRAME_TYPE_TELEMETRY_WIDE, &wide_telemetry_payload(70000, &[1, 150]));
+        let devices = HardDevice::factory(&frame, frame.len()).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].get_address(), DeviceAddress::Wide { informer_id: 70000, sensor_id: 1 });
+        assert_eq!(devices[0].get_id(), (70001 << 16) | 1);
+        assert_eq!(devices[1].get_temperature(), 150.0);
+        assert_eq!(devices[1].get_humidity(), 0.5);
+    }
+
+    #[test]
+    fn test_v2_wide_telemetry_keeps_legacy_ids() {
+        let frame = encode(FRAME_TYPE_TELEMETRY_WIDE, &wide_telemetry_payload(12, &[1, 2]));
+        let devices = HardDevice::factory(&frame, frame.len()).unwrap();
+        assert_eq!(devices[0].get_id(), 1201);
+        assert_eq!(devices[1].get_id(), 1202);
+    }
+
+    #[test]
+    fn test_v2_wide_telemetry_targets() {
+        let frame = encode(FRAME_TYPE_TELEMETRY_WIDE, &wide_telemetry_payload(70000, &[3]));
+        let mut devices = HardDevice::factory(&frame, frame.len()).unwrap();
+        devices[0].set_config(&"{\"temperature\": 4.0, \"humidity\": 50.0}".to_owned()).unwrap();
+        let targets = devices[0].target_as_bytes().unwrap();
+        assert_eq!(u32::from_le_bytes(targets[0..4].try_into().unwrap()), 70000);
+        assert_eq!(u16::from_le_bytes(targets[4..6].try_into().unwrap()), 3);
+        assert_eq!(f32::from_le_bytes(targets[6..10].try_into().unwrap()), 50.0);
+        assert_eq!(f32::from_le_bytes(targets[10..14].try_into().unwrap()), 4.0);
+    }
+
+    #[test]
+    fn test_wide_telemetry_count_mismatch() {
+        let mut payload = wide_telemetry_payload(70000, &[1, 2]);
+        payload.pop();
+        let frame = encode(FRAME_TYPE_TELEMETRY_WIDE, &payload);
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::TooManyDevices));
+    }
+
+    #[test]
+    fn test_wide_telemetry_truncated_header() {
+        let frame = encode(FRAME_TYPE_TELEMETRY_WIDE, &[1, 0, 0]);
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::TruncatedHeader));
+    }
+
+    #[test]
+    fn test_non_finite_reading() {
+        let mut payload = telemetry_payload();
+        payload[10..14].copy_from_slice(&f32::NAN.to_le_bytes());
+        let frame = encode(FRAME_TYPE_TELEMETRY, &payload);
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::NonFiniteReading { device_id: 702 }));
+
+        payload[10..14].copy_from_slice(&f32::INFINITY.to_le_bytes());
+        let devices = HardDevice::factory(&payload, payload.len());
+        assert_eq!(devices.err(), Some(ProtocolError::NonFiniteReading { device_id: 702 }), "Legacy frames are checked too");
+    }
+
+    #[test]
+    fn test_ack_response() {
+        let targets = vec![vec![1, 2, 3], vec![4, 5, 6]];
+        let frame = Response::Ack(targets).encode();
+        match decode(&frame).unwrap() {
+            Frame::V2 { frame_type, payload } => {
+                assert_eq!(frame_type, FRAME_TYPE_ACK);
+                assert_eq!(payload, &[2, 1, 2, 3, 4, 5, 6]);
+            }
+            Frame::Legacy(_) => panic!("Response is not a v2 frame"),
+        }
+    }
+
+    #[test]
+    fn test_nack_response() {
+        let frame = Response::Nack(ProtocolError::BadChecksum).encode();
+        match decode(&frame).unwrap() {
+            Frame::V2 { frame_type, payload } => {
+                assert_eq!(frame_type, FRAME_TYPE_NACK);
+                assert_eq!(payload, &[ProtocolError::BadChecksum.code()]);
+            }
+            Frame::Legacy(_) => panic!("Response is not a v2 frame"),
+        }
+    }
+
+    #[test]
+    fn test_no_config_response() {
+        let frame = Response::NoConfig(vec![701, 1 << 40]).encode();
+        match decode(&frame).unwrap() {
+            Frame::V2 { frame_type, payload } => {
+                assert_eq!(frame_type, FRAME_TYPE_NO_CONFIG);
+                assert_eq!(payload[0], 2);
+                assert_eq!(u64::from_le_bytes(payload[1..9].try_into().unwrap()), 701);
+                assert_eq!(u64::from_le_bytes(payload[9..17].try_into().unwrap()), 1 << 40);
+            }
+            Frame::Legacy(_) => panic!("Response is not a v2 frame"),
+        }
+    }
+
+    fn tlv_record(tag: u8, value: &[u8]) -> Vec<u8> {
+        let mut record = vec![tag, value.len() as u8];
+        record.extend_from_slice(value);
+        record
+    }
+
+    #[test]
+    fn test_v2_tlv_telemetry() {
+        let mut payload = 70000u32.to_le_bytes().to_vec();
+        payload.extend(tlv_record(0x00, &5u16.to_le_bytes()));
+        payload.extend(tlv_record(0x01, &3.5f32.to_le_bytes()));
+        payload.extend(tlv_record(0x03, &[1]));
+        payload.extend(tlv_record(0x04, &1.25f32.to_le_bytes()));
+        payload.extend(tlv_record(0x00, &6u16.to_le_bytes()));
+        payload.extend(tlv_record(0x05, &3.3f32.to_le_bytes()));
+        payload.extend(tlv_record(0x06, &[0xB5]));
+        payload.extend(tlv_record(0x42, &7i16.to_le_bytes()));
+        let frame = encode(FRAME_TYPE_TELEMETRY_TLV, &payload);
+
+        let devices = HardDevice::factory(&frame, frame.len()).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].get_address(), DeviceAddress::Wide { informer_id: 70000, sensor_id: 5 });
+        assert_eq!(devices[0].get_temperature(), 3.5);
+        assert_eq!(devices[0].get_reading(Channel::DoorOpen), Some(1.0));
+        assert_eq!(devices[0].get_reading(Channel::CompressorCurrent), Some(1.25));
+        assert_eq!(devices[0].get_reading(Channel::Humidity), None);
+        assert_eq!(devices[1].get_readings().len(), 3);
+        assert_eq!(devices[1].get_reading(Channel::Rssi), Some(-75.0));
+        assert_eq!(
+            devices[1].as_json(),
+            format!(
+                "{{\"id\":{}, \"name\":\"Device {}\", \"battery_voltage\":3.3, \"rssi\":-75, \"channel_66\":7, \"received_at\":{}}}",
+                devices[1].get_id(),
+                devices[1].get_id(),
+                devices[1].get_received_at().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_tlv_sequence_and_device_time() {
+        let mut payload = 9u32.to_le_bytes().to_vec();
+        payload.extend(tlv_record(0xF0, &42u32.to_le_bytes()));
+        payload.extend(tlv_record(0x00, &1u16.to_le_bytes()));
+        payload.extend(tlv_record(0x01, &3.5f32.to_le_bytes()));
+        payload.extend(tlv_record(0x00, &2u16.to_le_bytes()));
+        payload.extend(tlv_record(0xF1, &1700000000000u64.to_le_bytes()));
+        let frame = encode(FRAME_TYPE_TELEMETRY_TLV, &payload);
+
+        let devices = HardDevice::factory(&frame, frame.len()).unwrap();
+        assert_eq!(devices.len(), 2);
+        for device in devices {
+            assert_eq!(device.get_sequence(), Some(42));
+            assert_eq!(device.get_device_time(), Some(1700000000000));
+            assert!(device.get_received_at().is_some());
+        }
+    }
+
+    #[test]
+    fn test_tlv_bad_sequence_record() {
+        let mut payload = 9u32.to_le_bytes().to_vec();
+        payload.extend(tlv_record(0xF0, &[1, 2]));
+        let frame = encode(FRAME_TYPE_TELEMETRY_TLV, &payload);
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::BadRecord { tag: 0xF0 }));
+    }
+
+    #[test]
+    fn test_batch_telemetry() {
+        let mut payload = 9u32.to_le_bytes().to_vec();
+        payload.extend(tlv_record(0xF0, &43u32.to_le_bytes()));
+        payload.extend(tlv_record(0x00, &1u16.to_le_bytes()));
+        payload.extend(tlv_record(0xF2, &2000u64.to_le_bytes()));
+        payload.extend(tlv_record(0x01, &4.0f32.to_le_bytes()));
+        payload.extend(tlv_record(0xF2, &1000u64.to_le_bytes()));
+        payload.extend(tlv_record(0x01, &3.0f32.to_le_bytes()));
+        payload.extend(tlv_record(0x03, &[1]));
+        payload.extend(tlv_record(0x00, &2u16.to_le_bytes()));
+        payload.extend(tlv_record(0xF2, &1500u64.to_le_bytes()));
+        payload.extend(tlv_record(0x02, &60.0f32.to_le_bytes()));
+        let frame = encode(FRAME_TYPE_TELEMETRY_BATCH, &payload);
+
+        let samples = HardDevice::factory(&frame, frame.len()).unwrap();
+        let times: Vec<Option<u64>> = samples.iter().map(|s| s.get_device_time()).collect();
+        assert_eq!(times, vec![Some(1000), Some(1500), Some(2000)], "Samples are ordered by time");
+        assert_eq!(samples[0].get_temperature(), 3.0);
+        assert_eq!(samples[0].get_reading(Channel::DoorOpen), Some(1.0));
+        assert_eq!(samples[1].get_address(), DeviceAddress::Wide { informer_id: 9, sensor_id: 2 });
+        assert_eq!(samples[2].get_temperature(), 4.0);
+        for sample in &samples {
+            assert!(sample.is_backfilled());
+            assert_eq!(sample.get_sequence(), Some(43));
+            assert!(sample.as_json().ends_with(", \"backfilled\":true}"));
+        }
+    }
+
+    #[test]
+    fn test_batch_reading_without_sample_time() {
+        let mut payload = 9u32.to_le_bytes().to_vec();
+        payload.extend(tlv_record(0x00, &1u16.to_le_bytes()));
+        payload.extend(tlv_record(0xF2, &1000u64.to_le_bytes()));
+        payload.extend(tlv_record(0x00, &2u16.to_le_bytes()));
+        payload.extend(tlv_record(0x01, &4.0f32.to_le_bytes()));
+        let frame = encode(FRAME_TYPE_TELEMETRY_BATCH, &payload);
+        let samples = HardDevice::factory(&frame, frame.len());
+        assert_eq!(samples.err(), Some(ProtocolError::BadRecord { tag: 0x01 }));
+    }
+
+    #[test]
+    fn test_sample_time_in_live_frame() {
+        let mut payload = 9u32.to_le_bytes().to_vec();
+        payload.extend(tlv_record(0x00, &1u16.to_le_bytes()));
+        payload.extend(tlv_record(0xF2, &1000u64.to_le_bytes()));
+        let frame = encode(FRAME_TYPE_TELEMETRY_TLV, &payload);
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::BadRecord { tag: 0xF2 }));
+    }
+
+    #[test]
+    fn test_tlv_reading_without_sensor() {
+        let mut payload = 1u32.to_le_bytes().to_vec();
+        payload.extend(tlv_record(0x01, &3.5f32.to_le_bytes()));
+        let frame = encode(FRAME_TYPE_TELEMETRY_TLV, &payload);
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::BadRecord { tag: 0x01 }));
+    }
+
+    #[test]
+    fn test_tlv_bad_value_length() {
+        let mut payload = 1u32.to_le_bytes().to_vec();
+        payload.extend(tlv_record(0x00, &[1]));
+        let frame = encode(FRAME_TYPE_TELEMETRY_TLV, &payload);
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::BadRecord { tag: 0x00 }));
+    }
+
+    #[test]
+    fn test_tlv_truncated_record() {
+        let mut payload = 1u32.to_le_bytes().to_vec();
+        payload.extend(tlv_record(0x00, &1u16.to_le_bytes()));
+        payload.extend(&[0x01, 4, 0]);
+        let frame = encode(FRAME_TYPE_TELEMETRY_TLV, &payload);
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::TruncatedPayload));
+    }
+
+    #[test]
+    fn test_tlv_too_many_devices() {
+        let mut payload = 1u32.to_le_bytes().to_vec();
+        for sensor_id in 0..300u16 {
+            payload.extend(tlv_record(0x00, &sensor_id.to_le_bytes()));
+        }
+        let frame = encode(FRAME_TYPE_TELEMETRY_TLV, &payload);
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::TooManyDevices));
+    }
+
+    #[test]
+    fn test_legacy_is_not_wrapped() {
+        let payload = telemetry_payload();
+        match decode(&payload).unwrap() {
+            Frame::Legacy(body) => assert_eq!(body, payload.as_slice()),
+            Frame::V2 { .. } => panic!("Legacy frame is detected as v2"),
+        }
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let mut frame = encode(FRAME_TYPE_TELEMETRY, &telemetry_payload());
+        frame[V2_HEADER_SIZE + 3] ^= 0x10;
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::BadChecksum));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut frame = encode(FRAME_TYPE_TELEMETRY, &telemetry_payload());
+        frame[2] = 3;
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::UnsupportedVersion(3)));
+    }
+
+    #[test]
+    fn test_unsupported_frame_type() {
+        let frame = encode(0x7F, &telemetry_payload());
+        let devices = HardDevice::factory(&frame, frame.len());
+        assert_eq!(devices.err(), Some(ProtocolError::UnsupportedFrameType(0x7F)));
+    }
+
+    #[test]
+    fn test_truncated_v2_frame() {
+        let frame = encode(FRAME_TYPE_TELEMETRY, &telemetry_payload());
+        let devices = HardDevice::factory(&frame, frame.len() - 1);
+        assert_eq!(devices.err(), Some(ProtocolError::TruncatedPayload));
+    }
+}
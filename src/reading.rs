@@ -0,0 +1,127 @@
+// Telemetry channels reported by sensors. Tags are shared with TLV frames,
+// unknown tags are kept as is so new sensor types pass through without changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Temperature,
+    Humidity,
+    DoorOpen,
+    CompressorCurrent,
+    BatteryVoltage,
+    Rssi,
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Reading {
+    pub channel: Channel,
+    pub value: f32,
+}
+
+impl Channel {
+    pub fn from_tag(tag: u8) -> Channel {
+        match tag {
+            0x01 => Channel::Temperature,
+            0x02 => Channel::Humidity,
+            0x03 => Channel::DoorOpen,
+            0x04 => Channel::CompressorCurrent,
+            0x05 => Channel::BatteryVoltage,
+            0x06 => Channel::Rssi,
+            tag => Channel::Other(tag),
+        }
+    }
+
+    pub fn tag(&self) -> u8 {
+        match self {
+            Channel::Temperature => 0x01,
+            Channel::Humidity => 0x02,
+            Channel::DoorOpen => 0x03,
+            Channel::CompressorCurrent => 0x04,
+            Channel::BatteryVoltage => 0x05,
+            Channel::Rssi => 0x06,
+            Channel::Other(tag) => *tag,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Channel::Temperature => "temperature".to_owned(),
+            Channel::Humidity => "humidity".to_owned(),
+            Channel::DoorOpen => "door_open".to_owned(),
+            Channel::CompressorCurrent => "compressor_current".to_owned(),
+            Channel::BatteryVoltage => "battery_voltage".to_owned(),
+            Channel::Rssi => "rssi".to_owned(),
+            Channel::Other(tag) => format!("channel_{}", tag),
+        }
+    }
+
+    // Inverse of name, documents refer to channels the way as_json prints them
+    pub fn from_name(name: &str) -> Option<Channel> {
+        match name {
+            "temperature" => Some(Channel::Temperature),
+            "humidity" => Some(Channel::Humidity),
+            "door_open" => Some(Channel::DoorOpen),
+            "compressor_current" => Some(Channel::CompressorCurrent),
+            "battery_voltage" => Some(Channel::BatteryVoltage),
+            "rssi" => Some(Channel::Rssi),
+            _ => {
+                let tag: u8 = name.strip_prefix("channel_")?.parse().ok()?;
+                Some(Channel::from_tag(tag))
+            }
+        }
+    }
+
+    // Values are f32 when 4 bytes long and integers otherwise, RSSI is the only signed byte
+    pub fn decode_value(&self, data: &[u8]) -> Option<f32> {
+        match data.len() {
+            1 if *self == Channel::Rssi => Some(data[0] as i8 as f32),
+            1 => Some(data[0] as f32),
+            2 => Some(i16::from_le_bytes([data[0], data[1]]) as f32),
+            4 => Some(f32::from_le_bytes(data.try_into().unwrap())),
+            _ => None,
+        }
+    }
+}
+
+impl Reading {
+    pub fn new(channel: Channel, value: f32) -> Reading {
+        Reading { channel, value }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tags_round_trip() {
+        for tag in 1..=255u8 {
+            assert_eq!(Channel::from_tag(tag).tag(), tag);
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(Channel::Temperature.name(), "temperature");
+        assert_eq!(Channel::from_tag(0x05).name(), "battery_voltage");
+        assert_eq!(Channel::from_tag(0x42).name(), "channel_66");
+    }
+
+    #[test]
+    fn test_from_name() {
+        for tag in 1..=255u8 {
+            let channel = Channel::from_tag(tag);
+            assert_eq!(Channel::from_name(&channel.name()), Some(channel));
+        }
+        assert_eq!(Channel::from_name("pressure"), None);
+        assert_eq!(Channel::from_name("channel_256"), None);
+    }
+
+    #[test]
+    fn test_decode_value() {
+        assert_eq!(Channel::DoorOpen.decode_value(&[1]), Some(1.0));
+        assert_eq!(Channel::Rssi.decode_value(&[0xB5]), Some(-75.0));
+        assert_eq!(Channel::Rssi.decode_value(&(-75i16).to_le_bytes()), Some(-75.0));
+        assert_eq!(Channel::CompressorCurrent.decode_value(&1.25f32.to_le_bytes()), Some(1.25));
+        assert_eq!(Channel::BatteryVoltage.decode_value(&[1, 2, 3]), None);
+    }
+}
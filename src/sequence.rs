@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[derive(Debug, PartialEq)]
+pub enum SequenceStatus {
+    First,
+    InOrder,
+    Gap { missed: u32 },
+    Duplicate,
+    Replay,
+}
+
+// Last sequence number seen from every informer. Numbers are compared with
+// wrapping arithmetic, 0 means the informer restarted its counter.
+#[derive(Default)]
+pub struct SequenceTracker {
+    last_sequence: Mutex<HashMap<u32, u32>>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> SequenceTracker {
+        SequenceTracker {
+            last_sequence: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn check(&self, informer_id: u32, sequence: u32) -> SequenceStatus {
+        let mut last_sequence = self.last_sequence.lock().unwrap();
+        let last = match last_sequence.get(&informer_id) {
+            Some(last) if sequence != 0 => *last,
+            _ => {
+                last_sequence.insert(informer_id, sequence);
+                return SequenceStatus::First;
+            }
+        };
+        let distance = sequence.wrapping_sub(last);
+        if distance == 0 {
+            return SequenceStatus::Duplicate;
+        }
+        if distance > u32::MAX / 2 {
+            return SequenceStatus::Replay;
+        }
+        last_sequence.insert(informer_id, sequence);
+        if distance == 1 {
+            return SequenceStatus::InOrder;
+        }
+        SequenceStatus::Gap { missed: distance - 1 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_order() {
+        let tracker = SequenceTracker::new();
+        assert_eq!(tracker.check(1, 10), SequenceStatus::First);
+        assert_eq!(tracker.check(1, 11), SequenceStatus::InOrder);
+        assert_eq!(tracker.check(2, 11), SequenceStatus::First, "Informers are tracked separately");
+        assert_eq!(tracker.check(1, 12), SequenceStatus::InOrder);
+    }
+
+    #[test]
+    fn test_gap() {
+        let tracker = SequenceTracker::new();
+        tracker.check(1, 10);
+        assert_eq!(tracker.check(1, 14), SequenceStatus::Gap { missed: 3 });
+        assert_eq!(tracker.check(1, 15), SequenceStatus::InOrder);
+    }
+
+    #[test]
+    fn test_duplicate_and_replay() {
+        let tracker = SequenceTracker::new();
+        tracker.check(1, 10);
+        tracker.check(1, 11);
+        assert_eq!(tracker.check(1, 11), SequenceStatus::Duplicate);
+        assert_eq!(tracker.check(1, 5), SequenceStatus::Replay);
+        assert_eq!(tracker.check(1, 12), SequenceStatus::InOrder, "Rejected numbers don't move the counter");
+    }
+
+    #[test]
+    fn test_wrap_and_restart() {
+        let tracker = SequenceTracker::new();
+        tracker.check(1, u32::MAX);
+        assert_eq!(tracker.check(1, 1), SequenceStatus::Gap { missed: 1 });
+        assert_eq!(tracker.check(1, 0), SequenceStatus::First);
+        assert_eq!(tracker.check(1, 1), SequenceStatus::InOrder);
+    }
+}
@@ -1,16 +1,32 @@
 use std::{
-    io::{Read, Write},
+    io::{self, Read, Write},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
 use std::net::TcpListener;
 
+use async_std::{io::WriteExt, task};
+use log::error;
+
+use crate::command::CommandKind;
+use crate::datacache::Datacache;
+use crate::external::abstract_external::ExternalDatabase;
+use crate::target_store::TargetStore;
+
+// Requests are small JSON documents, anything larger is refused
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct ServiceServer {
     services_warmup_counter: Arc<AtomicUsize>,
     service_port: u16,
+    external_database: Option<Arc<dyn ExternalDatabase>>,
+    target_store: Option<Arc<TargetStore>>,
+    config_cache: Option<Arc<Datacache>>,
 }
 
 impl ServiceServer {
@@ -18,8 +34,23 @@ impl ServiceServer {
         return ServiceServer {
             services_warmup_counter: counter,
             service_port: port,
+            external_database: None,
+            target_store: None,
+            config_cache: None,
         };
     }
+    pub fn with_external_database(mut self, external_database: Arc<dyn ExternalDatabase>) -> ServiceServer {
+        self.external_database = Some(external_database);
+        self
+    }
+    pub fn with_target_store(mut self, target_store: Arc<TargetStore>) -> ServiceServer {
+        self.target_store = Some(target_store);
+        self
+    }
+    pub fn with_config_cache(mut self, config_cache: Arc<Datacache>) -> ServiceServer {
+        self.config_cache = Some(config_cache);
+        self
+    }
     pub fn is_ready(&self) -> bool {
         self.services_warmup_counter.load(Ordering::Relaxed) == 0
     }
@@ -34,14 +65,24 @@ impl ServiceServer {
                 Ok(mut stream) => {
                     let now: chrono::DateTime<chrono::Utc> = chrono::Utc::now();
                     let formated_date = now.format("%a, %d %b %Y %T GMT");
-                    let mut buf = [0u8; 1024];
-                    if stream.read(&mut buf).is_err() {
-                        continue;
-                    }
-                    let data = String::from_utf8_lossy(&buf);
+                    let request = match stream.set_read_timeout(Some(READ_TIMEOUT)).and_then(|_| read_request(&mut stream)) {
+                        Ok(request) => request,
+                        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                            let response = format!("HTTP/1.1 413 Payload Too Large\nDate: {}\nServer: BE Server\nContent-Length: 0\n\n", formated_date);
+                            if let Err(e) = stream.write_all(response.as_bytes()) {
+                                error!("Can't answer oversized request: {}", e);
+                            }
+                            continue;
+                        }
+                        Err(_) => continue,
+                    };
+                    let data = String::from_utf8_lossy(&request);
 
                     let by_lines: Vec<&str> = data.split('\n').collect();
                     let first_line: Vec<&str> = by_lines[0].split(" ").collect();
+                    if first_line.len() < 2 {
+                        continue;
+                    }
                     let method = first_line[0];
                     let path = first_line[1];
 
@@ -58,18 +99,61 @@ impl ServiceServer {
                             response.len(),
                             response
                         );
-                        stream.write(data_as_text.as_bytes()).unwrap();
+                        if let Err(e) = stream.write_all(data_as_text.as_bytes()) {
+                            error!("Can't answer status request: {}", e);
+                        }
+                        continue;
+                    }
+                    if method == "GET" && path == "/metrics" {
+                        let response = self.metrics();
+                        let data_as_text = format!(
+                            "HTTP/1.1 200 OK\nDate: {}\nServer: BE Server\nContent-Type: text/plain; version=0.0.4\nContent-Length: {}\n\n{}",
+                            formated_date,
+                            response.len(),
+                            response
+                        );
+                        if let Err(e) = stream.write_all(data_as_text.as_bytes()) {
+                            error!("Can't answer metrics request: {}", e);
+                        }
+                        continue;
+                    }
+                    if let Some((status, body)) = self.cache_request(method, path) {
+                        let data_as_text = format!(
+                            "HTTP/1.1 {}\nDate: {}\nServer: BE Server\nContent-Type: application/json\nContent-Length: {}\n\n{}",
+                            status,
+                            formated_date,
+                            body.len(),
+                            body
+                        );
+                        if let Err(e) = stream.write_all(data_as_text.as_bytes()) {
+                            error!("Can't answer cache request: {}", e);
+                        }
+                        continue;
+                    }
+                    // The database answers in a task, status and metrics requests don't wait for it
+                    if let ("POST", Some(device_id)) = (method, command_path(path)) {
+                        let request_body = request_body(&data).to_owned();
+                        let external_database = self.external_database.clone();
+                        let formated_date = formated_date.to_string();
+                        task::spawn(async move {
+                            let (status, body) = enqueue_command(external_database.as_deref(), device_id, &request_body).await;
+                            let data_as_text = format!(
+                                "HTTP/1.1 {}\nDate: {}\nServer: BE Server\nContent-Type: application/json\nContent-Length: {}\n\n{}",
+                                status,
+                                formated_date,
+                                body.len(),
+                                body
+                            );
+                            let mut stream = async_std::net::TcpStream::from(stream);
+                            if let Err(e) = stream.write_all(data_as_text.as_bytes()).await {
+                                error!("Can't answer command request: {}", e);
+                            }
+                        });
                         continue;
                     }
-                    stream
-                        .write(
-                            format!(
-                                "HTTP/1.1 404\nDate: {}\nServer: BE Server\n\n",
-                                formated_date
-                            )
-                            .as_bytes(),
-                        )
-                        .unwrap();
+                    if let Err(e) = stream.write_all(format!("HTTP/1.1 404\nDate: {}\nServer: BE Server\n\n", formated_date).as_bytes()) {
+                        error!("Can't answer request: {}", e);
+                    }
                 }
                 Err(err) => {
                     panic!("{}", err);
@@ -77,6 +161,143 @@ impl ServiceServer {
             }
         }
     }
+
+    // Prometheus text format
+    fn metrics(&self) -> String {
+        let mut metrics = String::new();
+        if let Some(target_store) = &self.target_store {
+            metrics.push_str("# HELP be_fallback_devices Devices running on fallback targets\n");
+            metrics.push_str("# TYPE be_fallback_devices gauge\n");
+            metrics.push_str(&format!("be_fallback_devices {}\n", target_store.fallback_count()));
+        }
+        if let Some(config_cache) = &self.config_cache {
+            let stats = config_cache.get_stats();
+            let counters = [
+                ("hits", "Device configs served from cache", stats.hits),
+                ("misses", "Device configs read from database", stats.misses),
+                ("evictions", "Device configs evicted from full cache", stats.evictions),
+                ("expiries", "Device configs found past their lifetime", stats.expiries),
+            ];
+            for (name, help, value) in counters {
+                metrics.push_str(&format!("# HELP be_config_cache_{}_total {}\n", name, help));
+                metrics.push_str(&format!("# TYPE be_config_cache_{}_total counter\n", name));
+                metrics.push_str(&format!("be_config_cache_{}_total {}\n", name, value));
+            }
+            metrics.push_str("# HELP be_config_cache_entries Device configs in cache\n");
+            metrics.push_str("# TYPE be_config_cache_entries gauge\n");
+            metrics.push_str(&format!("be_config_cache_entries {}\n", stats.size));
+        }
+        metrics
+    }
+
+    // GET /cache lists entries, DELETE /cache and DELETE /cache/{id} flush them.
+    // Flushing a device tells whether it was cached, flushing all counts entries.
+    fn cache_request(&self, method: &str, path: &str) -> Option<(&'static str, String)> {
+        let device_id = match path.strip_prefix("/cache") {
+            Some("") => None,
+            Some(device_id) => Some(device_id.strip_prefix('/')?.parse::<u64>().ok()?),
+            None => return None,
+        };
+        let config_cache = match &self.config_cache {
+            Some(config_cache) => config_cache,
+            None => return Some(("503 Service Unavailable", "{\"error\":\"No config cache\"}".to_owned())),
+        };
+        match (method, device_id) {
+            ("GET", None) => {
+                let stats = config_cache.get_stats();
+                let entries: Vec<serde_json::Value> = config_cache
+                    .get_entries()
+                    .iter()
+                    .map(|entry| serde_json::json!({ "id": entry.device_id, "age": entry.age.as_secs(), "missing": entry.missing }))
+                    .collect();
+                let body = serde_json::json!({
+                    "hits": stats.hits,
+                    "misses": stats.misses,
+                    "evictions": stats.evictions,
+                    "expiries": stats.expiries,
+                    "size": stats.size,
+                    "capacity": stats.capacity,
+                    "entries": entries,
+                });
+                Some(("200 OK", body.to_string()))
+            }
+            ("DELETE", None) => Some(("200 OK", serde_json::json!({ "invalidated": config_cache.invalidate_all() }).to_string())),
+            ("DELETE", Some(device_id)) => {
+                let removed = config_cache.invalidate(device_id);
+                Some(("200 OK", serde_json::json!({ "invalidated": removed }).to_string()))
+            }
+            _ => None,
+        }
+    }
+
+}
+
+// POST /devices/{id}/commands {"command": "set_reporting_interval", "seconds": 60}
+async fn enqueue_command(external_database: Option<&dyn ExternalDatabase>, device_id: u64, body: &str) -> (&'static str, String) {
+    let kind = match parse_command(body) {
+        Some(kind) => kind,
+        None => return ("400 Bad Request", "{\"error\":\"Unknown command\"}".to_owned()),
+    };
+    let external_database = match external_database {
+        Some(external_database) => external_database,
+        None => return ("503 Service Unavailable", "{\"error\":\"No command queue\"}".to_owned()),
+    };
+    match external_database.enqueue_command(device_id, kind).await {
+        Ok(command_id) => ("201 Created", format!("{{\"id\":{}}}", command_id)),
+        Err(e) => ("503 Service Unavailable", serde_json::json!({ "error": e.to_string() }).to_string()),
+    }
+}
+
+// Headers and as much of the body as Content-Length announces
+fn read_request(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let size = header_size(&request).map(|header_size| header_size + content_length(&request[..header_size]));
+        if size.unwrap_or(request.len()) > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Request is too large"));
+        }
+        if let Some(size) = size.filter(|size| request.len() >= *size) {
+            request.truncate(size);
+            return Ok(request);
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(request);
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+}
+
+fn header_size(request: &[u8]) -> Option<usize> {
+    let crlf = request.windows(4).position(|w| w == b"\r\n\r\n").map(|position| position + 4);
+    crlf.or_else(|| request.windows(2).position(|w| w == b"\n\n").map(|position| position + 2))
+}
+
+fn content_length(headers: &[u8]) -> usize {
+    String::from_utf8_lossy(headers)
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+fn command_path(path: &str) -> Option<u64> {
+    path.strip_prefix("/devices/")?.strip_suffix("/commands")?.parse().ok()
+}
+
+fn request_body(request: &str) -> &str {
+    let body = match request.find("\r\n\r\n") {
+        Some(position) => &request[position + 4..],
+        None => request.find("\n\n").map(|position| &request[position + 2..]).unwrap_or_default(),
+    };
+    body.trim_end_matches('\0')
+}
+
+fn parse_command(body: &str) -> Option<CommandKind> {
+    let request: serde_json::Value = serde_json::from_str(body).ok()?;
+    CommandKind::from_json(request["command"].as_str()?, &request)
 }
 
 #[cfg(test)]
@@ -155,4 +376,117 @@ mod tests {
         let response3 = block_on(reqwest::get("http://localhost:32143/unknown_handler")).unwrap();
         assert_eq!(response3.status(), StatusCode::NOT_FOUND);
     }
+
+    #[test]
+    fn test_command_request() {
+        assert_eq!(command_path("/devices/1201/commands"), Some(1201));
+        assert_eq!(command_path("/devices/x/commands"), None);
+        assert_eq!(command_path("/devices/1201"), None);
+
+        let request = "POST /devices/1201/commands HTTP/1.1\r\nHost: localhost\r\n\r\n{\"command\": \"identify\", \"seconds\": 5}\0\0";
+        assert_eq!(parse_command(request_body(request)), Some(CommandKind::Identify { seconds: 5 }));
+        assert_eq!(parse_command("{\"command\": \"reboot\"}"), Some(CommandKind::Reboot));
+        assert_eq!(parse_command("{\"seconds\": 5}"), None);
+        assert_eq!(parse_command(""), None);
+    }
+
+    #[test]
+    fn test_enqueue_without_database() {
+        assert_eq!(block_on(enqueue_command(None, 1201, "{\"command\": \"reboot\"}")).0, "503 Service Unavailable");
+        assert_eq!(block_on(enqueue_command(None, 1201, "{\"command\": \"boot\"}")).0, "400 Bad Request");
+    }
+
+    #[test]
+    fn test_read_request() {
+        let headers = "POST /devices/1201/commands HTTP/1.1\r\nHost: localhost\r\ncontent-length: 21\r\n\r\n";
+        let body = "{\"command\": \"reboot\"}";
+        let mut split = headers.as_bytes().chain(&body.as_bytes()[..5]).chain(&body.as_bytes()[5..]);
+        let request = read_request(&mut split).unwrap();
+        assert_eq!(String::from_utf8(request).unwrap(), format!("{}{}", headers, body), "Read until the body is complete");
+
+        let request = read_request(&mut "GET /status HTTP/1.1\r\n\r\nGET".as_bytes()).unwrap();
+        assert_eq!(request, b"GET /status HTTP/1.1\r\n\r\n");
+
+        let oversized = format!("POST /devices/1201/commands HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_REQUEST_SIZE);
+        assert_eq!(read_request(&mut oversized.as_bytes()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let endless = vec![b'a'; MAX_REQUEST_SIZE + 1];
+        assert_eq!(read_request(&mut endless.as_slice()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_command_off_thread() {
+        struct SlowDatabase {}
+
+        #[async_trait::async_trait]
+        impl ExternalDatabase for SlowDatabase {
+            async fn get_device_config(&self, device_id: u64) -> Result<String, std::io::Error> {
+                Ok(format!("config{}", device_id))
+            }
+            async fn enqueue_command(&self, _device_id: u64, _kind: CommandKind) -> Result<u64, std::io::Error> {
+                async_std::task::sleep(Duration::from_millis(500)).await;
+                Ok(7)
+            }
+        }
+
+        thread::spawn(move || {
+            ServiceServer::new(Arc::new(AtomicUsize::new(0)), 32147).with_external_database(Arc::new(SlowDatabase {})).run_listener();
+        });
+        thread::sleep(Duration::from_millis(100));
+        let command = thread::spawn(|| {
+            let response = block_on(reqwest::Client::new().post("http://localhost:32147/devices/1201/commands").body("{\"command\": \"reboot\"}").send()).unwrap();
+            (response.status(), block_on(response.text()).unwrap())
+        });
+        thread::sleep(Duration::from_millis(100));
+        let started = std::time::Instant::now();
+        let status = block_on(block_on(reqwest::get("http://localhost:32147/status")).unwrap().text()).unwrap();
+        assert_eq!(status, "OK");
+        assert!(started.elapsed() < Duration::from_millis(300), "Status doesn't wait for the command");
+        assert_eq!(command.join().unwrap(), (StatusCode::CREATED, "{\"id\":7}".to_owned()));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let target_store = Arc::new(TargetStore::default());
+        let server = ServiceServer::new(Arc::new(AtomicUsize::new(0)), 0).with_target_store(target_store.clone());
+        target_store.mark_fallback(1201);
+        assert!(server.metrics().contains("\nbe_fallback_devices 1\n"));
+        assert_eq!(ServiceServer::new(Arc::new(AtomicUsize::new(0)), 0).metrics(), "");
+    }
+
+    #[test]
+    fn test_cache_request() {
+        struct MockExternalDatabase {}
+
+        #[async_trait::async_trait]
+        impl ExternalDatabase for MockExternalDatabase {
+            async fn get_device_config(&self, device_id: u64) -> Result<String, std::io::Error> {
+                Ok(format!("config{}", device_id))
+            }
+        }
+
+        let server = ServiceServer::new(Arc::new(AtomicUsize::new(0)), 0);
+        assert_eq!(server.cache_request("GET", "/cache").unwrap().0, "503 Service Unavailable");
+        assert_eq!(server.cache_request("GET", "/status"), None);
+
+        let config_cache = Arc::new(Datacache::new(Arc::new(MockExternalDatabase {})));
+        let server = server.with_config_cache(config_cache.clone());
+        block_on(config_cache.get_device_config(1201)).unwrap();
+        block_on(config_cache.get_device_config(1201)).unwrap();
+        block_on(config_cache.get_device_config(1202)).unwrap();
+
+        let (status, body) = server.cache_request("GET", "/cache").unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status, "200 OK");
+        assert_eq!((body["hits"].as_u64(), body["misses"].as_u64()), (Some(1), Some(2)));
+        assert_eq!(body["entries"][1]["id"].as_u64(), Some(1202));
+        assert!(server.metrics().contains("\nbe_config_cache_hits_total 1\n"));
+        assert!(server.metrics().contains("\nbe_config_cache_entries 2\n"));
+
+        assert_eq!(server.cache_request("DELETE", "/cache/1201").unwrap().1, "{\"invalidated\":true}");
+        assert_eq!(server.cache_request("DELETE", "/cache/1201").unwrap().1, "{\"invalidated\":false}");
+        assert_eq!(server.cache_request("DELETE", "/cache/x"), None);
+        assert_eq!(server.cache_request("DELETE", "/cache").unwrap().1, "{\"invalidated\":1}");
+        assert_eq!(server.cache_request("POST", "/cache"), None);
+        assert_eq!(config_cache.get_size(), 0);
+    }
 }
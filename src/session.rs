@@ -0,0 +1,703 @@
+use async_std::io as aio;
+use be_server::device::Device;
+use be_server::device::HardDevice;
+use be_server::document::{self, ContentFormat};
+use be_server::external::abstract_external::ExternalDatabase;
+use be_server::framing::FrameDecoder;
+use be_server::protocol;
+use be_server::command::{self, CommandStatus};
+use be_server::device_id::DeviceAddress;
+use be_server::firmware::{ChunkRequest, FirmwareStatus};
+use be_server::protocol::{
+    Frame, ProtocolError, Response, FRAME_TYPE_CBOR_DOCUMENT, FRAME_TYPE_COMMAND_ACK, FRAME_TYPE_FIRMWARE_REQUEST, FRAME_TYPE_FIRMWARE_STATUS,
+    FRAME_TYPE_JSON_DOCUMENT,
+};
+use be_server::sequence::SequenceStatus;
+use be_server::setpoint::Setpoint;
+use log::{debug, error};
+use std::collections::HashSet;
+use std::error::Error;
+use std::io;
+use be_server::tls::InformerIdentity;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::state::GlobalState;
+
+// Frames with sequence number are checked against previous ones of the informer
+pub fn is_fresh(devices: &[HardDevice], state: &GlobalState) -> bool {
+    let (informer_id, sequence) = match devices.first() {
+        Some(device) => match device.get_sequence() {
+            Some(sequence) => (device.get_address().informer_id(), sequence),
+            None => return true,
+        },
+        None => return true,
+    };
+    match state.check_sequence(informer_id, sequence) {
+        SequenceStatus::First | SequenceStatus::InOrder => true,
+        SequenceStatus::Gap { missed } => {
+            error!("Informer {} lost {} frames before {}", informer_id, missed, sequence);
+            true
+        }
+        SequenceStatus::Duplicate => {
+            error!("Informer {} repeated frame {}", informer_id, sequence);
+            false
+        }
+        SequenceStatus::Replay => {
+            error!("Informer {} sent outdated frame {}", informer_id, sequence);
+            false
+        }
+    }
+}
+
+// Connections authenticated with a client certificate only report for informers it names
+fn authorize(devices: Vec<HardDevice>, identity: Option<&InformerIdentity>) -> Result<Vec<HardDevice>, ProtocolError> {
+    if let Some(identity) = identity {
+        identity.check(&devices)?;
+    }
+    Ok(devices)
+}
+
+// Targets from DeviceConfig pass the setpoint limits of the device type. Devices
+// without a usable config get their fallback target, it has passed the limits already
+// or is the configured default. Targets from the config are returned, they are
+// recorded once sent.
+pub async fn configure(hdevice: &mut HardDevice, state: &GlobalState, external_database: &dyn ExternalDatabase) -> Result<Option<Setpoint>, io::Error> {
+    let config = match external_database.get_device_config(hdevice.get_id()).await {
+        Ok(config_str) => hdevice.set_config(&config_str).map_err(io::Error::from),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = config {
+        let target = state.fallback_target(hdevice.get_id()).ok_or(e)?;
+        debug!("Device {} uses fallback target {:?}", hdevice.get_id(), target);
+        hdevice.set_targets(target.temperature, target.humidity);
+        return Ok(None);
+    }
+    // Without the type the device gets the limits for all types
+    let device_type = match state.needs_device_type() {
+        true => external_database.get_device_type(hdevice.get_id()).await.unwrap_or_else(|e| {
+            error!("Can't get type of device {}: {}", hdevice.get_id(), e);
+            None
+        }),
+        false => None,
+    };
+    match state.limit_targets(hdevice, device_type.as_deref()) {
+        true => Ok(hdevice.get_target_temperature().zip(hdevice.get_target_humidity()).map(|(temperature, humidity)| Setpoint { temperature, humidity })),
+        false => Err(io::Error::new(io::ErrorKind::InvalidData, "Target is refused by setpoint limits")),
+    }
+}
+
+// Response to send back with the config targets and commands in it, see
+// confirm_delivery. Signed replies answer a frame with a valid signature.
+#[derive(Debug, Default)]
+pub struct Reply {
+    pub response: Vec<u8>,
+    pub targets: Vec<(u64, Setpoint)>,
+    pub commands: Vec<u64>,
+    pub signed: bool,
+}
+
+impl From<Vec<u8>> for Reply {
+    fn from(response: Vec<u8>) -> Reply {
+        Reply { response, ..Default::default() }
+    }
+}
+
+// Config targets become the last known good ones and commands count as
+// delivered once the reply is sent
+pub async fn confirm_delivery(reply: &Reply, state: &GlobalState, external_database: &dyn ExternalDatabase) {
+    state.record_targets(&reply.targets);
+    if reply.commands.is_empty() {
+        return;
+    }
+    if let Err(e) = external_database.set_command_status(&reply.commands, CommandStatus::Delivered).await {
+        error!("Can't mark commands {:?} delivered: {}", reply.commands, e);
+    }
+}
+
+// Publishes readings and collects devices that got their config and the config
+// targets, ids of devices without config are returned separately
+async fn process_devices(
+    devices: Vec<HardDevice>,
+    publish: bool,
+    state: &GlobalState,
+    external_database: &dyn ExternalDatabase,
+) -> (Vec<HardDevice>, Vec<u64>, Vec<(u64, Setpoint)>) {
+    let mut configured = Vec::new();
+    let mut no_config = Vec::new();
+    let mut targets = Vec::new();
+    for mut hdevice in devices {
+        let dev_json = hdevice.as_json();
+        let device_id = hdevice.get_id_str();
+        if publish {
+            state.new_device(hdevice.clone());
+            println!("{}", dev_json);
+        }
+        // History from store-and-forward batches doesn't need targets
+        if hdevice.is_backfilled() {
+            continue;
+        }
+        match configure(&mut hdevice, state, external_database).await {
+            Ok(target) => {
+                targets.extend(target.map(|target| (hdevice.get_id(), target)));
+                configured.push(hdevice);
+            }
+            Err(e) => {
+                println!("No config for device {}: {}", device_id, e);
+                no_config.push(hdevice.get_id());
+            }
+        }
+    }
+    (configured, no_config, targets)
+}
+
+// Commands go out after the targets, their ids are returned for confirm_delivery
+async fn deliver_commands(device_ids: &[u64], external_database: &dyn ExternalDatabase) -> (Vec<u8>, Vec<u64>) {
+    let commands = match external_database.get_pending_commands(device_ids).await {
+        Ok(commands) => commands,
+        Err(e) => {
+            error!("Can't get commands for devices {:?}: {}", device_ids, e);
+            return (Vec::new(), Vec::new());
+        }
+    };
+    let command_ids: Vec<u64> = commands.iter().map(|c| c.id).collect();
+    (commands.into_iter().flat_map(|c| Response::Command(c).encode()).collect(), command_ids)
+}
+
+// Frames naming a device directly pass the same checks as readings of the device
+fn authorize_device(device_id: u64, signer: Option<u32>, identity: Option<&InformerIdentity>, state: &GlobalState) -> Result<(), ProtocolError> {
+    let informer_id = DeviceAddress::from_device_id(device_id)
+        .map(|address| address.informer_id())
+        .ok_or(ProtocolError::ForeignInformer { informer_id: 0 })?;
+    state.authenticate_informer(informer_id, signer)?;
+    match identity {
+        Some(identity) if !identity.allows(informer_id) => Err(ProtocolError::ForeignInformer { informer_id }),
+        _ => Ok(()),
+    }
+}
+
+// Commands are acknowledged for devices of the informer that signed the frame
+// or the connection certificate names. Plain connections only acknowledge
+// commands of devices that reported on them.
+fn authorize_acknowledgement(
+    device_id: u64,
+    signer: Option<u32>,
+    identity: Option<&InformerIdentity>,
+    session_devices: &HashSet<u64>,
+    state: &GlobalState,
+) -> Result<(), ProtocolError> {
+    authorize_device(device_id, signer, identity, state)?;
+    if signer.is_none() && identity.is_none() && !session_devices.contains(&device_id) {
+        let informer_id = DeviceAddress::from_device_id(device_id).map(|address| address.informer_id()).unwrap_or_default();
+        return Err(ProtocolError::ForeignInformer { informer_id });
+    }
+    Ok(())
+}
+
+async fn acknowledge_commands(
+    payload: &[u8],
+    signer: Option<u32>,
+    identity: Option<&InformerIdentity>,
+    session_devices: &HashSet<u64>,
+    state: &GlobalState,
+    external_database: &dyn ExternalDatabase,
+) -> Vec<u8> {
+    let command_ids = match command::parse_acknowledgement(payload) {
+        Ok(command_ids) => command_ids,
+        Err(e) => return Response::Nack(e).encode(),
+    };
+    let commands = match external_database.get_commands(&command_ids).await {
+        Ok(commands) => commands,
+        Err(e) => {
+            error!("Can't get commands {:?}: {}", command_ids, e);
+            return Response::Ack(Vec::new()).encode();
+        }
+    };
+    let mut acknowledged = Vec::new();
+    let mut rejected = None;
+    for command in commands {
+        match authorize_acknowledgement(command.device_id, signer, identity, session_devices, state) {
+            Ok(()) => acknowledged.push(command.id),
+            Err(e) => {
+                error!("Command {} can't be acknowledged: {}", command.id, e);
+                rejected = rejected.or(Some(e));
+            }
+        }
+    }
+    if !acknowledged.is_empty() {
+        if let Err(e) = external_database.set_command_status(&acknowledged, CommandStatus::Acknowledged).await {
+            error!("Can't mark commands {:?} acknowledged: {}", acknowledged, e);
+        }
+    }
+    match rejected {
+        Some(e) => Response::Nack(e).encode(),
+        None => Response::Ack(Vec::new()).encode(),
+    }
+}
+
+// Reported versions are recorded, the offer is made whenever the version
+// differs from the desired one, so rollbacks work the same way as updates
+async fn firmware_status(
+    payload: &[u8],
+    signer: Option<u32>,
+    identity: Option<&InformerIdentity>,
+    state: &GlobalState,
+    external_database: &dyn ExternalDatabase,
+) -> Vec<u8> {
+    let status = match FirmwareStatus::parse(payload).and_then(|status| authorize_device(status.device_id, signer, identity, state).map(|_| status)) {
+        Ok(status) => status,
+        Err(e) => return reject(e, true),
+    };
+    if let Err(e) = external_database.report_firmware_version(status.device_id, status.version).await {
+        error!("Can't record firmware of device {}: {}", status.device_id, e);
+    }
+    match external_database.get_firmware_offer(status.device_id).await {
+        Ok(Some(offer)) if offer.version != status.version => {
+            debug!("Offer firmware {} to device {} running {}", offer.version, status.device_id, status.version);
+            Response::FirmwareOffer(offer).encode()
+        }
+        Ok(_) => Response::Ack(Vec::new()).encode(),
+        Err(e) => {
+            error!("Can't get firmware for device {}: {}", status.device_id, e);
+            Response::Ack(Vec::new()).encode()
+        }
+    }
+}
+
+// Informers ask for chunks at their own offsets, so an interrupted transfer
+// continues where it stopped and a failed checksum starts over from zero
+async fn firmware_chunk(
+    payload: &[u8],
+    signer: Option<u32>,
+    identity: Option<&InformerIdentity>,
+    state: &GlobalState,
+    external_database: &dyn ExternalDatabase,
+) -> Vec<u8> {
+    let request = match ChunkRequest::parse(payload).and_then(|request| authorize_device(request.device_id, signer, identity, state).map(|_| request)) {
+        Ok(request) => request,
+        Err(e) => return reject(e, true),
+    };
+    match external_database.get_firmware_chunk(request.device_id, request.offset, request.length).await {
+        Ok(Some(chunk)) if chunk.version == request.version => Response::FirmwareChunk(chunk).encode(),
+        Ok(_) => Response::Nack(ProtocolError::FirmwareUnavailable).encode(),
+        Err(e) => {
+            error!("Can't read firmware for device {}: {}", request.device_id, e);
+            Response::Nack(ProtocolError::FirmwareUnavailable).encode()
+        }
+    }
+}
+
+fn reject(e: ProtocolError, is_v2: bool) -> Vec<u8> {
+    error!("Frame rejected: {}", e);
+    if is_v2 {
+        return Response::Nack(e).encode();
+    }
+    Vec::new()
+}
+
+// Everything to send back for the frame. v1 informers get bare target bytes
+// and nothing on errors, v2 informers get response frames and queued commands.
+// Session devices are the ones that reported on the connection so far.
+pub async fn process_frame(
+    frame: &[u8],
+    identity: Option<&InformerIdentity>,
+    session_devices: &mut HashSet<u64>,
+    state: &GlobalState,
+    external_database: &dyn ExternalDatabase,
+) -> Reply {
+    let is_v2 = protocol::is_v2(frame);
+    let (frame, signer) = match state.open_frame(frame) {
+        Ok(opened) => opened,
+        Err(e) => return reject(e, is_v2).into(),
+    };
+    let reply = process_opened_frame(&frame, is_v2, signer, identity, session_devices, state, external_database).await;
+    Reply { signed: signer.is_some(), ..reply }
+}
+
+async fn process_opened_frame(
+    frame: &[u8],
+    is_v2: bool,
+    signer: Option<u32>,
+    identity: Option<&InformerIdentity>,
+    session_devices: &mut HashSet<u64>,
+    state: &GlobalState,
+    external_database: &dyn ExternalDatabase,
+) -> Reply {
+    match protocol::decode(frame) {
+        Ok(Frame::V2 { frame_type: FRAME_TYPE_COMMAND_ACK, payload }) => {
+            return acknowledge_commands(payload, signer, identity, session_devices, state, external_database).await.into()
+        }
+        Ok(Frame::V2 { frame_type: FRAME_TYPE_FIRMWARE_STATUS, payload }) => {
+            return firmware_status(payload, signer, identity, state, external_database).await.into()
+        }
+        Ok(Frame::V2 { frame_type: FRAME_TYPE_FIRMWARE_REQUEST, payload }) => {
+            return firmware_chunk(payload, signer, identity, state, external_database).await.into()
+        }
+        Ok(Frame::V2 { frame_type: frame_type @ (FRAME_TYPE_JSON_DOCUMENT | FRAME_TYPE_CBOR_DOCUMENT), payload }) => {
+            let format = ContentFormat::from_frame_type(frame_type).expect("document frame type");
+            return process_document(payload, format, signer, identity, session_devices, state, external_database).await;
+        }
+        _ => {}
+    }
+    let devices = match HardDevice::factory(frame, frame.len())
+        .and_then(|devices| state.authenticate_devices(&devices, signer).map(|_| devices))
+        .and_then(|devices| authorize(devices, identity))
+    {
+        Ok(devices) => devices,
+        Err(e) => return reject(e, is_v2).into(),
+    };
+
+    // Repeated frames are answered as usual, only their readings are not published again
+    let mut device_ids: Vec<u64> = devices.iter().map(|d| d.get_id()).collect();
+    device_ids.sort_unstable();
+    device_ids.dedup();
+    session_devices.extend(&device_ids);
+    let publish = is_fresh(&devices, state);
+    let (configured, no_config, config_targets) = process_devices(devices, publish, state, external_database).await;
+    let targets: Vec<Vec<u8>> = configured.iter().filter_map(|hdevice| hdevice.target_as_bytes().ok()).collect();
+    if !is_v2 {
+        return Reply { response: targets.concat(), targets: config_targets, ..Default::default() };
+    }
+    let mut response = Response::Ack(targets).encode();
+    if !no_config.is_empty() {
+        response.extend(Response::NoConfig(no_config).encode());
+    }
+    let (commands, command_ids) = deliver_commands(&device_ids, external_database).await;
+    response.extend(commands);
+    Reply { response, targets: config_targets, commands: command_ids, signed: false }
+}
+
+// Telemetry documents get a response document in the same format, errors included
+async fn process_document(
+    payload: &[u8],
+    format: ContentFormat,
+    signer: Option<u32>,
+    identity: Option<&InformerIdentity>,
+    session_devices: &mut HashSet<u64>,
+    state: &GlobalState,
+    external_database: &dyn ExternalDatabase,
+) -> Reply {
+    let response_type = format.response_frame_type();
+    let devices = match document::decode(payload, format)
+        .and_then(|document| HardDevice::from_document(&document))
+        .and_then(|devices| state.authenticate_devices(&devices, signer).map(|_| devices))
+        .and_then(|devices| authorize(devices, identity))
+    {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!("Document rejected: {}", e);
+            return protocol::encode(response_type, &document::encode(&document::error_response(&e), format)).into();
+        }
+    };
+
+    session_devices.extend(devices.iter().map(|d| d.get_id()));
+    let publish = is_fresh(&devices, state);
+    let (configured, no_config, targets) = process_devices(devices, publish, state, external_database).await;
+    let response = document::encode(&document::response(&configured, &no_config), format);
+    Reply { response: protocol::encode(response_type, &response), targets, ..Default::default() }
+}
+
+// Serves one informer connection: every frame is answered with targets, the
+// connection stays open until the informer closes it or stays silent too long
+pub async fn process_socket<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    identity: Option<InformerIdentity>,
+    state: &GlobalState,
+    external_database: &dyn ExternalDatabase,
+) -> Result<(), Box<dyn Error>> {
+    let idle_timeout = state.get_idle_timeout();
+    let mut buf = [0; 1024];
+    let mut decoder = FrameDecoder::new();
+    let mut session_devices = HashSet::new();
+
+    loop {
+        let n = match aio::timeout(idle_timeout, socket.read(&mut buf)).await {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                println!("Connection is idle for {:?}, closing", idle_timeout);
+                return Ok(());
+            }
+            read_result => read_result?,
+        };
+        if n == 0 {
+            if decoder.pending() > 0 {
+                println!("Connection closed with {} bytes of incomplete frame", decoder.pending());
+            }
+            return Ok(());
+        }
+        println!("read {} bytes", n);
+        decoder.push(&buf[..n]);
+
+        while let Some(frame) = decoder.next_frame() {
+            let reply = process_frame(&frame, identity.as_ref(), &mut session_devices, state, external_database).await;
+            socket.write_all(&reply.response).await?;
+            confirm_delivery(&reply, state, external_database).await;
+        }
+    }
+}
+
+// The handshake has the same time limit as an idle connection
+pub async fn process_tls_socket(acceptor: TlsAcceptor, socket: TcpStream, state: &GlobalState, external_database: &dyn ExternalDatabase) -> Result<(), Box<dyn Error>> {
+    let stream = aio::timeout(state.get_idle_timeout(), acceptor.accept(socket)).await?;
+    let identity = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| InformerIdentity::from_certificate(cert));
+    process_socket(stream, identity, state, external_database).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use async_std::task::block_on;
+    use async_trait::async_trait;
+    use clap::Parser;
+    use be_server::auth::{self, CounterStore, KeyStore};
+    use be_server::command::{Command, CommandKind};
+    use be_server::firmware::FirmwareChunk;
+    use be_server::protocol::FRAME_TYPE_TELEMETRY;
+    use be_server::setpoint::SetpointGuard;
+    use std::sync::mpsc::channel;
+    use std::sync::Mutex;
+
+    struct MockExternalDatabase {
+        config: Mutex<Result<String, io::ErrorKind>>,
+    }
+
+    #[async_trait]
+    impl ExternalDatabase for MockExternalDatabase {
+        async fn get_device_config(&self, _device_id: u64) -> Result<String, std::io::Error> {
+            self.config.lock().unwrap().clone().map_err(|kind| io::Error::new(kind, "Mock"))
+        }
+    }
+
+    #[test]
+    fn test_fallback_targets() {
+        let (snd, _rcv) = channel::<HardDevice>();
+        let state = GlobalState::new(snd, ServerConfig::parse_from(["be-server", "--deftemp", "3", "--defhum", "40"]));
+        let database = MockExternalDatabase { config: Mutex::new(Ok("{\"temperature\": 4.5".to_owned())) };
+        let mut hdevice = HardDevice::from_device_id(1201).unwrap();
+
+        assert_eq!(block_on(configure(&mut hdevice, &state, &database)).unwrap(), None);
+        assert_eq!(hdevice.get_target_temperature(), Some(3.0), "Default for malformed config");
+
+        *database.config.lock().unwrap() = Ok("{\"temperature\": 4.5, \"humidity\": 55.0}".to_owned());
+        let target = block_on(configure(&mut hdevice, &state, &database)).unwrap();
+        assert_eq!(target, Some(Setpoint { temperature: 4.5, humidity: 55.0 }));
+        assert_eq!(hdevice.get_target_temperature(), Some(4.5));
+
+        *database.config.lock().unwrap() = Err(io::ErrorKind::NotConnected);
+        let mut hdevice = HardDevice::from_device_id(1201).unwrap();
+        block_on(configure(&mut hdevice, &state, &database)).unwrap();
+        assert_eq!(hdevice.get_target_temperature(), Some(3.0), "Targets are last known good once sent");
+
+        state.record_targets(&[(1201, target.unwrap())]);
+        let mut hdevice = HardDevice::from_device_id(1201).unwrap();
+        block_on(configure(&mut hdevice, &state, &database)).unwrap();
+        assert_eq!(hdevice.get_target_temperature(), Some(4.5), "Last known good target");
+        assert_eq!(hdevice.get_target_humidity(), Some(55.0));
+    }
+
+    struct UntypedDatabase;
+
+    #[async_trait]
+    impl ExternalDatabase for UntypedDatabase {
+        async fn get_device_config(&self, _device_id: u64) -> Result<String, std::io::Error> {
+            Ok("{\"temperature\": 4.5, \"humidity\": 55.0}".to_owned())
+        }
+        async fn get_device_type(&self, _device_id: u64) -> Result<Option<String>, std::io::Error> {
+            Err(io::Error::new(io::ErrorKind::NotConnected, "Mock"))
+        }
+    }
+
+    #[test]
+    fn test_untyped_limits() {
+        let (snd, _rcv) = channel::<HardDevice>();
+        let limits = r#"{"fridge": {"temperature": {"min": 5, "max": 8}}, "*": {"temperature": {"min": -40, "max": 3}}}"#;
+        let state = GlobalState::new(snd, ServerConfig::parse_from(["be-server"])).with_setpoint_limits(SetpointGuard::parse(limits).unwrap());
+        let mut hdevice = HardDevice::from_device_id(1201).unwrap();
+        block_on(configure(&mut hdevice, &state, &UntypedDatabase)).unwrap();
+        assert_eq!(hdevice.get_target_temperature(), Some(3.0), "Limits for all types without the device type");
+    }
+
+    #[test]
+    fn test_record_sent_targets() {
+        let (snd, _rcv) = channel::<HardDevice>();
+        let state = GlobalState::new(snd, ServerConfig::parse_from(["be-server"]));
+        let database = MockExternalDatabase { config: Mutex::new(Ok("{\"temperature\": 4.5, \"humidity\": 55.0}".to_owned())) };
+        let mut frame = vec![12, 1];
+        frame.extend_from_slice(&1.5f32.to_le_bytes());
+        frame.extend_from_slice(&40f32.to_le_bytes());
+
+        // The informer is gone before the targets are sent
+        let (mut informer, server) = tokio::io::duplex(64);
+        block_on(informer.write_all(&frame)).unwrap();
+        drop(informer);
+        assert!(block_on(process_socket(server, None, &state, &database)).is_err());
+        assert_eq!(state.get_target_store().get(1201), None);
+
+        let (mut informer, server) = tokio::io::duplex(64);
+        block_on(informer.write_all(&frame)).unwrap();
+        let session = std::thread::spawn(move || block_on(process_socket(server, None, &state, &database)).map_err(|e| e.to_string()).map(|_| state));
+        let mut response = [0; 12];
+        block_on(informer.read_exact(&mut response)).unwrap();
+        drop(informer);
+        let state = session.join().unwrap().unwrap();
+        assert_eq!(state.get_target_store().get(1201), Some(Setpoint { temperature: 4.5, humidity: 55.0 }));
+    }
+
+    #[test]
+    fn test_no_fallback() {
+        let (snd, _rcv) = channel::<HardDevice>();
+        let state = GlobalState::new(snd, ServerConfig::parse_from(["be-server"]));
+        let database = MockExternalDatabase { config: Mutex::new(Err(io::ErrorKind::NotFound)) };
+        let mut hdevice = HardDevice::from_device_id(1201).unwrap();
+        let e = block_on(configure(&mut hdevice, &state, &database)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    // Command 1 is for device 1201, command 2 for 1301
+    #[derive(Default)]
+    struct CommandDatabase {
+        acknowledged: Mutex<Vec<u64>>,
+    }
+
+    #[async_trait]
+    impl ExternalDatabase for CommandDatabase {
+        async fn get_device_config(&self, _device_id: u64) -> Result<String, std::io::Error> {
+            Ok("{\"temperature\": 4.5, \"humidity\": 55.0}".to_owned())
+        }
+        async fn get_commands(&self, command_ids: &[u64]) -> Result<Vec<Command>, std::io::Error> {
+            let commands = [(1, 1201), (2, 1301)].map(|(id, device_id)| Command { id, device_id, kind: CommandKind::Reboot });
+            Ok(commands.into_iter().filter(|c| command_ids.contains(&c.id)).collect())
+        }
+        async fn set_command_status(&self, command_ids: &[u64], status: CommandStatus) -> Result<(), std::io::Error> {
+            if status == CommandStatus::Acknowledged {
+                self.acknowledged.lock().unwrap().extend(command_ids);
+            }
+            Ok(())
+        }
+    }
+
+    fn acknowledgement(command_ids: &[u64]) -> Vec<u8> {
+        let mut payload = vec![command_ids.len() as u8];
+        command_ids.iter().for_each(|id| payload.extend_from_slice(&id.to_le_bytes()));
+        payload
+    }
+
+    #[test]
+    fn test_acknowledgement_scope() {
+        let (snd, _rcv) = channel::<HardDevice>();
+        let mut keys = KeyStore::default();
+        keys.insert(13, vec![7; 16]);
+        let state = GlobalState::new(snd, ServerConfig::parse_from(["be-server"])).with_keys(keys, CounterStore::default());
+        let database = CommandDatabase::default();
+        let mut session_devices = HashSet::new();
+        let ack = protocol::encode(FRAME_TYPE_COMMAND_ACK, &acknowledgement(&[1]));
+        let foreign = Response::Nack(ProtocolError::ForeignInformer { informer_id: 12 }).encode();
+
+        let response = block_on(process_frame(&ack, None, &mut session_devices, &state, &database)).response;
+        assert_eq!(response, foreign, "Device 1201 didn't report on the connection");
+
+        let mut telemetry = vec![12, 1];
+        telemetry.extend_from_slice(&1.5f32.to_le_bytes());
+        telemetry.extend_from_slice(&40f32.to_le_bytes());
+        block_on(process_frame(&protocol::encode(FRAME_TYPE_TELEMETRY, &telemetry), None, &mut session_devices, &state, &database));
+        assert_eq!(session_devices, HashSet::from([1201]));
+        let response = block_on(process_frame(&ack, None, &mut session_devices, &state, &database)).response;
+        assert_eq!(response, Response::Ack(Vec::new()).encode());
+        let response = block_on(process_frame(&ack, Some(&InformerIdentity::default()), &mut session_devices, &state, &database)).response;
+        assert_eq!(response, foreign, "Certificate doesn't name informer 12");
+
+        // Informer 13 has a key, its commands are only acknowledged in signed frames
+        let plain = protocol::encode(FRAME_TYPE_COMMAND_ACK, &acknowledgement(&[2]));
+        let response = block_on(process_frame(&plain, None, &mut session_devices, &state, &database)).response;
+        assert_eq!(response, Response::Nack(ProtocolError::Unauthenticated { informer_id: 13 }).encode());
+        let signed = auth::sign(&[7; 16], 13, 1, FRAME_TYPE_COMMAND_ACK, &acknowledgement(&[2]));
+        let response = block_on(process_frame(&signed, None, &mut HashSet::new(), &state, &database)).response;
+        assert_eq!(response, Response::Ack(Vec::new()).encode());
+        let signed = auth::sign(&[7; 16], 13, 2, FRAME_TYPE_COMMAND_ACK, &acknowledgement(&[1, 2]));
+        let response = block_on(process_frame(&signed, None, &mut session_devices, &state, &database)).response;
+        assert_eq!(response, foreign, "Informer 13 signed for a command of informer 12");
+
+        assert_eq!(*database.acknowledged.lock().unwrap(), vec![1, 2, 2]);
+    }
+
+    struct FirmwareDatabase;
+
+    #[async_trait]
+    impl ExternalDatabase for FirmwareDatabase {
+        async fn get_device_config(&self, device_id: u64) -> Result<String, std::io::Error> {
+            Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No config for {}", device_id)))
+        }
+        async fn get_firmware_chunk(&self, _device_id: u64, offset: u32, length: u16) -> Result<Option<FirmwareChunk>, std::io::Error> {
+            Ok(Some(FirmwareChunk { version: 5, offset, data: vec![0xAB; length as usize] }))
+        }
+    }
+
+    #[test]
+    fn test_firmware_authorization() {
+        let (snd, _rcv) = channel::<HardDevice>();
+        let mut keys = KeyStore::default();
+        keys.insert(13, vec![7; 16]);
+        let state = GlobalState::new(snd, ServerConfig::parse_from(["be-server"])).with_keys(keys, CounterStore::default());
+        let request = |device_id: u64| {
+            let mut payload = device_id.to_le_bytes().to_vec();
+            payload.extend_from_slice(&5u32.to_le_bytes());
+            payload.extend_from_slice(&0u32.to_le_bytes());
+            payload.extend_from_slice(&4u16.to_le_bytes());
+            payload
+        };
+        let chunk = Response::FirmwareChunk(FirmwareChunk { version: 5, offset: 0, data: vec![0xAB; 4] }).encode();
+        let process = |frame: Vec<u8>, identity: Option<&InformerIdentity>| block_on(process_frame(&frame, identity, &mut HashSet::new(), &state, &FirmwareDatabase)).response;
+
+        let plain = protocol::encode(FRAME_TYPE_FIRMWARE_REQUEST, &request(1201));
+        assert_eq!(process(plain.clone(), None), chunk);
+        assert_eq!(process(plain, Some(&InformerIdentity::default())), Response::Nack(ProtocolError::ForeignInformer { informer_id: 12 }).encode());
+
+        let plain = protocol::encode(FRAME_TYPE_FIRMWARE_REQUEST, &request(1301));
+        assert_eq!(process(plain, None), Response::Nack(ProtocolError::Unauthenticated { informer_id: 13 }).encode());
+        assert_eq!(process(auth::sign(&[7; 16], 13, 1, FRAME_TYPE_FIRMWARE_REQUEST, &request(1301)), None), chunk);
+        let foreign = auth::sign(&[7; 16], 13, 2, FRAME_TYPE_FIRMWARE_REQUEST, &request(1201));
+        assert_eq!(process(foreign, None), Response::Nack(ProtocolError::ForeignInformer { informer_id: 12 }).encode());
+
+        let mut status = 1301u64.to_le_bytes().to_vec();
+        status.extend_from_slice(&5u32.to_le_bytes());
+        let plain = protocol::encode(FRAME_TYPE_FIRMWARE_STATUS, &status);
+        assert_eq!(process(plain, None), Response::Nack(ProtocolError::Unauthenticated { informer_id: 13 }).encode());
+    }
+
+    #[test]
+    fn test_document_frames() {
+        let (snd, _rcv) = channel::<HardDevice>();
+        let mut keys = KeyStore::default();
+        keys.insert(13, vec![7; 16]);
+        let state = GlobalState::new(snd, ServerConfig::parse_from(["be-server"])).with_keys(keys, CounterStore::default());
+        let database = MockExternalDatabase { config: Mutex::new(Ok("{\"temperature\": 4.5, \"humidity\": 55.0}".to_owned())) };
+        let process = |frame: Vec<u8>| {
+            let response = block_on(process_frame(&frame, None, &mut HashSet::new(), &state, &database)).response;
+            match protocol::decode(&response) {
+                Ok(Frame::V2 { frame_type, payload }) => (frame_type, serde_json::from_slice::<serde_json::Value>(payload).unwrap()),
+                _ => panic!("Response is not a v2 frame"),
+            }
+        };
+
+        let document = |informer_id: u32| format!(r#"{{"informer": {}, "devices": [{{"sensor": 1, "temperature": 1.5, "humidity": 40}}]}}"#, informer_id);
+        let (frame_type, response) = process(protocol::encode(FRAME_TYPE_JSON_DOCUMENT, document(12).as_bytes()));
+        assert_eq!(frame_type, protocol::FRAME_TYPE_JSON_RESPONSE);
+        assert_eq!(response["targets"][0]["id"], 1201);
+        assert_eq!(response["targets"][0]["temperature"], 4.5);
+
+        let (frame_type, response) = process(protocol::encode(FRAME_TYPE_JSON_DOCUMENT, document(13).as_bytes()));
+        assert_eq!(frame_type, protocol::FRAME_TYPE_JSON_RESPONSE);
+        assert!(response.get("targets").is_none(), "Informers with a key have to sign documents");
+        let (_, response) = process(auth::sign(&[7; 16], 13, 1, FRAME_TYPE_JSON_DOCUMENT, document(13).as_bytes()));
+        assert_eq!(response["targets"][0]["id"], 1301);
+
+        let (_, response) = process(protocol::encode(FRAME_TYPE_JSON_DOCUMENT, b"{\"informer\": 12"));
+        assert_eq!(response["code"], ProtocolError::BadDocument.code());
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::reading::Channel;
+
+// Limits by device type (the `type` column of DeviceConfig), "*" applies to
+// types without their own entry:
+// {"fridge": {"temperature": {"min": 0, "max": 8, "max_step": 2},
+//             "humidity": {"min": 30, "max": 90}, "on_violation": "clamp"}}
+// Steps are measured from the last target sent to the device, see target_store.rs.
+pub const DEFAULT_LIMITS: &str = "*";
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelLimits {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    pub max_step: Option<f32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    #[default]
+    Clamp,
+    Refuse,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SetpointLimits {
+    #[serde(default)]
+    pub temperature: ChannelLimits,
+    #[serde(default)]
+    pub humidity: ChannelLimits,
+    #[serde(default)]
+    pub on_violation: Policy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Setpoint {
+    pub temperature: f32,
+    pub humidity: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Violation {
+    NotFinite,
+    OutOfRange,
+    StepTooLarge,
+}
+
+impl Violation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Violation::NotFinite => "not_finite",
+            Violation::OutOfRange => "out_of_range",
+            Violation::StepTooLarge => "step_too_large",
+        }
+    }
+}
+
+// Published for every target that didn't pass as is, applied is None when refused
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetpointEvent {
+    pub device_id: u64,
+    pub device_type: Option<String>,
+    pub channel: Channel,
+    pub violation: Violation,
+    pub requested: f32,
+    pub previous: Option<f32>,
+    pub applied: Option<f32>,
+}
+
+impl SetpointEvent {
+    pub fn as_json(&self) -> String {
+        serde_json::json!({
+            "event": "setpoint",
+            "id": self.device_id,
+            "type": self.device_type,
+            "channel": self.channel.name(),
+            "violation": self.violation.as_str(),
+            "requested": self.requested,
+            "previous": self.previous,
+            "applied": self.applied,
+        })
+        .to_string()
+    }
+}
+
+impl ChannelLimits {
+    // Range first, then the step from the previous target
+    fn limit(&self, requested: f32, previous: Option<f32>) -> (f32, Option<Violation>) {
+        if !requested.is_finite() {
+            return (requested, Some(Violation::NotFinite));
+        }
+        let mut value = requested;
+        let mut violation = None;
+        if let Some(min) = self.min.filter(|min| value < *min) {
+            value = min;
+            violation = Some(Violation::OutOfRange);
+        }
+        if let Some(max) = self.max.filter(|max| value > *max) {
+            value = max;
+            violation = Some(Violation::OutOfRange);
+        }
+        if let (Some(previous), Some(max_step)) = (previous, self.max_step) {
+            if (value - previous).abs() > max_step {
+                value = previous + max_step.copysign(value - previous);
+                violation = violation.or(Some(Violation::StepTooLarge));
+            }
+        }
+        (value, violation)
+    }
+}
+
+// Checks targets from DeviceConfig before they are sent to hardware
+#[derive(Default)]
+pub struct SetpointGuard {
+    limits: HashMap<String, SetpointLimits>,
+}
+
+impl SetpointGuard {
+    pub fn load(path: &str) -> io::Result<SetpointGuard> {
+        SetpointGuard::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> io::Result<SetpointGuard> {
+        let limits: HashMap<String, SetpointLimits> =
+            serde_json::from_str(content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let reversed = limits.iter().find(|(_, l)| {
+            [l.temperature, l.humidity].iter().any(|c| matches!((c.min, c.max), (Some(min), Some(max)) if min > max))
+        });
+        if let Some((device_type, _)) = reversed {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Min is above max for {}", device_type)));
+        }
+        Ok(SetpointGuard { limits })
+    }
+
+    pub fn len(&self) -> usize {
+        self.limits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    // Device types only matter when some type has its own limits
+    pub fn needs_device_type(&self) -> bool {
+        self.limits.keys().any(|device_type| device_type != DEFAULT_LIMITS)
+    }
+
+    fn limits_for(&self, device_type: Option<&str>) -> SetpointLimits {
+        device_type
+            .and_then(|device_type| self.limits.get(device_type))
+            .or_else(|| self.limits.get(DEFAULT_LIMITS))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Target to send with events for every limited channel, or the events
+    // alone when it is refused. Non-finite targets are always refused.
+    pub fn apply(
+        &self,
+        device_id: u64,
+        device_type: Option<&str>,
+        requested: Setpoint,
+        previous: Option<Setpoint>,
+    ) -> Result<(Setpoint, Vec<SetpointEvent>), Vec<SetpointEvent>> {
+        let limits = self.limits_for(device_type);
+
+        let (temperature, temperature_violation) = limits.temperature.limit(requested.temperature, previous.map(|p| p.temperature));
+        let (humidity, humidity_violation) = limits.humidity.limit(requested.humidity, previous.map(|p| p.humidity));
+        let refused = limits.on_violation == Policy::Refuse
+            || [temperature_violation, humidity_violation].contains(&Some(Violation::NotFinite));
+
+        let mut events = Vec::new();
+        let channels = [
+            (Channel::Temperature, temperature_violation, requested.temperature, temperature, previous.map(|p| p.temperature)),
+            (Channel::Humidity, humidity_violation, requested.humidity, humidity, previous.map(|p| p.humidity)),
+        ];
+        for (channel, violation, requested, applied, previous) in channels {
+            if let Some(violation) = violation {
+                events.push(SetpointEvent {
+                    device_id,
+                    device_type: device_type.map(str::to_owned),
+                    channel,
+                    violation,
+                    requested,
+                    previous,
+                    applied: if refused { None } else { Some(applied) },
+                });
+            }
+        }
+        if refused && !events.is_empty() {
+            return Err(events);
+        }
+        Ok((Setpoint { temperature, humidity }, events))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: &str = r#"{
+        "fridge": {"temperature": {"min": 0, "max": 8, "max_step": 2}, "humidity": {"min": 30, "max": 90}},
+        "freezer": {"temperature": {"min": -30, "max": -10}, "on_violation": "refuse"},
+        "*": {"temperature": {"min": -40, "max": 40}}
+    }"#;
+
+    fn setpoint(temperature: f32, humidity: f32) -> Setpoint {
+        Setpoint { temperature, humidity }
+    }
+
+    #[test]
+    fn test_clamp() {
+        let guard = SetpointGuard::parse(LIMITS).unwrap();
+        assert_eq!(guard.apply(1201, Some("fridge"), setpoint(4.0, 50.0), None), Ok((setpoint(4.0, 50.0), vec![])));
+
+        let (target, events) = guard.apply(1202, Some("fridge"), setpoint(40.0, 20.0), None).unwrap();
+        assert_eq!(target, setpoint(8.0, 30.0));
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].violation, Violation::OutOfRange);
+        assert_eq!(events[0].requested, 40.0);
+        assert_eq!(events[0].applied, Some(8.0));
+        assert_eq!(events[1].channel, Channel::Humidity);
+    }
+
+    #[test]
+    fn test_max_step() {
+        let guard = SetpointGuard::parse(LIMITS).unwrap();
+        let (target, events) = guard.apply(1201, Some("fridge"), setpoint(7.0, 50.0), Some(setpoint(2.0, 50.0))).unwrap();
+        assert_eq!(target, setpoint(4.0, 50.0));
+        assert_eq!(events[0].violation, Violation::StepTooLarge);
+        assert_eq!(events[0].previous, Some(2.0));
+
+        // Ramps one step per update
+        let (target, _) = guard.apply(1201, Some("fridge"), setpoint(7.0, 50.0), Some(target)).unwrap();
+        assert_eq!(target, setpoint(6.0, 50.0));
+        assert_eq!(guard.apply(1201, Some("fridge"), setpoint(7.0, 50.0), Some(target)), Ok((setpoint(7.0, 50.0), vec![])));
+        assert_eq!(guard.apply(1201, Some("fridge"), setpoint(0.0, 50.0), Some(setpoint(7.0, 50.0))).unwrap().0, setpoint(5.0, 50.0));
+    }
+
+    #[test]
+    fn test_refuse() {
+        let guard = SetpointGuard::parse(LIMITS).unwrap();
+        let events = guard.apply(1301, Some("freezer"), setpoint(18.0, 50.0), Some(setpoint(-18.0, 50.0))).unwrap_err();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].applied, None);
+        assert_eq!(events[0].previous, Some(-18.0));
+        assert!(events[0].as_json().contains("\"violation\":\"out_of_range\""));
+    }
+
+    #[test]
+    fn test_default_limits() {
+        let guard = SetpointGuard::parse(LIMITS).unwrap();
+        assert!(guard.needs_device_type());
+        assert_eq!(guard.apply(1, Some("display"), setpoint(60.0, 50.0), None).unwrap().0, setpoint(40.0, 50.0));
+        assert_eq!(guard.apply(2, None, setpoint(-60.0, 50.0), None).unwrap().0, setpoint(-40.0, 50.0));
+
+        let unlimited = SetpointGuard::default();
+        assert!(!unlimited.needs_device_type());
+        assert_eq!(unlimited.apply(3, None, setpoint(99.0, 200.0), None), Ok((setpoint(99.0, 200.0), vec![])));
+        let events = unlimited.apply(3, None, setpoint(f32::NAN, 50.0), None).unwrap_err();
+        assert_eq!(events[0].violation, Violation::NotFinite);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(SetpointGuard::parse("{\"fridge\": {\"temperature\": {\"min\": 8, \"max\": 0}}}").is_err());
+        assert!(SetpointGuard::parse("{\"fridge\": {\"temprature\": {\"min\": 0}}}").is_err());
+        assert!(SetpointGuard::parse("{\"fridge\": {\"on_violation\": \"ignore\"}}").is_err());
+    }
+}
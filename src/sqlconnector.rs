@@ -1,8 +1,19 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
 use async_std::task::block_on;
 use async_trait::async_trait;
-use sqlx::{postgres::PgPoolOptions, query};
+use log::{debug, error};
+use sqlx::{postgres::{PgListener, PgPoolOptions}, query};
 
+use crate::command::{Command, CommandKind, CommandStatus};
 use crate::external::abstract_external::ExternalDatabase;
+use crate::firmware::{FirmwareChunk, FirmwareOffer};
+
+// Channel of migrations/0003_device_config_notify.sql
+pub const CONFIG_CHANNEL: &str = "device_config";
 
 #[derive(Default)]
 struct PostgressDatabaseConfig {
@@ -16,24 +27,151 @@ struct PostgressDatabaseConfig {
 #[derive(Default)]
 pub struct PostgressDatabase {
     config: PostgressDatabaseConfig,
-    pool: Option<sqlx::Pool<sqlx::Postgres>>,
+    pool: RwLock<Option<sqlx::Pool<sqlx::Postgres>>>,
 }
 
 #[async_trait]
 impl ExternalDatabase for PostgressDatabase {
-    async fn get_device_config(&mut self, key: &String) -> Result<String, std::io::Error> {
+    async fn get_device_config(&self, device_id: u64) -> Result<String, std::io::Error> {
         const TABLE_NAME: &'static str = "DeviceConfig";
         let tab_name = TABLE_NAME;
-        println!("Getting config for device: {}", key);
-        let pool = self.pool.as_ref().expect("No connection to database");
-        let id = key.parse::<i32>().expect("Error parsing key");
+        println!("Getting config for device: {}", device_id);
+        let id = i64::try_from(device_id)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Bad device id {}", device_id)))?;
         let query = format!("SELECT config FROM {} WHERE id = $1", tab_name); 
         let config:(sqlx::types::JsonValue,) = sqlx::query_as(query.as_str())
             .bind(id)
-            .fetch_one(pool)
-            .await.expect("Error fetching data from database");
+            .fetch_one(&self.pool()?)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => std::io::Error::new(std::io::ErrorKind::NotFound, format!("No config for device {}", device_id)),
+                e => std::io::Error::new(std::io::ErrorKind::Other, e),
+            })?;
         Ok(config.0.to_string().to_owned())
     }
+
+    async fn get_device_type(&self, device_id: u64) -> Result<Option<String>, std::io::Error> {
+        let device_type: Option<(Option<String>,)> = sqlx::query_as("SELECT type FROM DeviceConfig WHERE id = $1")
+            .bind(device_id as i64)
+            .fetch_optional(&self.pool()?)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(device_type.and_then(|(device_type,)| device_type))
+    }
+
+    async fn get_pending_commands(&self, device_ids: &[u64]) -> Result<Vec<Command>, std::io::Error> {
+        let device_ids: Vec<i64> = device_ids.iter().map(|id| *id as i64).collect();
+        let rows: Vec<(i64, i64, String, sqlx::types::JsonValue)> = sqlx::query_as(
+            "SELECT id, device_id, command, args FROM DeviceCommand WHERE device_id = ANY($1) AND status = 'pending' ORDER BY id",
+        )
+        .bind(device_ids)
+        .fetch_all(&self.pool()?)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(commands_from_rows(rows))
+    }
+
+    async fn get_commands(&self, command_ids: &[u64]) -> Result<Vec<Command>, std::io::Error> {
+        let command_ids: Vec<i64> = command_ids.iter().map(|id| *id as i64).collect();
+        let rows: Vec<(i64, i64, String, sqlx::types::JsonValue)> =
+            sqlx::query_as("SELECT id, device_id, command, args FROM DeviceCommand WHERE id = ANY($1) ORDER BY id")
+                .bind(command_ids)
+                .fetch_all(&self.pool()?)
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(commands_from_rows(rows))
+    }
+
+    async fn set_command_status(&self, command_ids: &[u64], status: CommandStatus) -> Result<(), std::io::Error> {
+        // Statuses only move forward, a late delivery mark can't undo an acknowledgement
+        let previous = match status {
+            CommandStatus::Pending => vec![],
+            CommandStatus::Delivered => vec![CommandStatus::Pending.as_str()],
+            CommandStatus::Acknowledged => vec![CommandStatus::Pending.as_str(), CommandStatus::Delivered.as_str()],
+        };
+        let command_ids: Vec<i64> = command_ids.iter().map(|id| *id as i64).collect();
+        sqlx::query("UPDATE DeviceCommand SET status = $1, updated_at = now() WHERE id = ANY($2) AND status = ANY($3)")
+            .bind(status.as_str())
+            .bind(command_ids)
+            .bind(previous)
+            .execute(&self.pool()?)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(())
+    }
+
+    async fn enqueue_command(&self, device_id: u64, kind: CommandKind) -> Result<u64, std::io::Error> {
+        let id: (i64,) = sqlx::query_as("INSERT INTO DeviceCommand (device_id, command, args) VALUES ($1, $2, $3) RETURNING id")
+            .bind(device_id as i64)
+            .bind(kind.name())
+            .bind(kind.args())
+            .fetch_one(&self.pool()?)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(id.0 as u64)
+    }
+
+    async fn report_firmware_version(&self, device_id: u64, version: u32) -> Result<(), std::io::Error> {
+        sqlx::query(
+            "INSERT INTO DeviceFirmware (device_id, version) VALUES ($1, $2) \
+             ON CONFLICT (device_id) DO UPDATE SET version = EXCLUDED.version, updated_at = now()",
+        )
+        .bind(device_id as i64)
+        .bind(version as i64)
+        .execute(&self.pool()?)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(())
+    }
+
+    // Only the digest leaves the database, images are read chunk by chunk
+    async fn get_firmware_offer(&self, device_id: u64) -> Result<Option<FirmwareOffer>, std::io::Error> {
+        let offer: Option<(i64, i32, Vec<u8>)> = sqlx::query_as(
+            "SELECT f.version, length(f.image), sha256(f.image) FROM Firmware f \
+             JOIN DeviceConfig d ON d.type = f.type WHERE d.id = $1",
+        )
+        .bind(device_id as i64)
+        .fetch_optional(&self.pool()?)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(offer.map(|(version, size, sha256)| FirmwareOffer {
+            version: version as u32,
+            size: size as u32,
+            sha256,
+        }))
+    }
+
+    // substring() of bytea takes integer positions, images are far below 2 GiB
+    async fn get_firmware_chunk(&self, device_id: u64, offset: u32, length: u16) -> Result<Option<FirmwareChunk>, std::io::Error> {
+        let start = i32::try_from(offset as i64 + 1)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Bad firmware offset {}", offset)))?;
+        let chunk: Option<(i64, Vec<u8>)> = sqlx::query_as(
+            "SELECT f.version, substring(f.image from $2 for $3) FROM Firmware f \
+             JOIN DeviceConfig d ON d.type = f.type WHERE d.id = $1",
+        )
+        .bind(device_id as i64)
+        .bind(start)
+        .bind(length as i32)
+        .fetch_optional(&self.pool()?)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        Ok(chunk.map(|(version, data)| FirmwareChunk {
+            version: version as u32,
+            offset,
+            data,
+        }))
+    }
+}
+
+fn commands_from_rows(rows: Vec<(i64, i64, String, sqlx::types::JsonValue)>) -> Vec<Command> {
+    let mut commands = Vec::new();
+    for (id, device_id, name, args) in rows {
+        match CommandKind::from_json(&name, &args) {
+            Some(kind) => commands.push(Command { id: id as u64, device_id: device_id as u64, kind }),
+            None => error!("Skip malformed command {} for device {}: {} {}", id, device_id, name, args),
+        }
+    }
+    commands
 }
 
 impl PostgressDatabaseConfig {
@@ -60,7 +198,7 @@ impl PostgressDatabase {
             password: password,
             basename: basename,
         };
-        let mut instance = PostgressDatabase {
+        let instance = PostgressDatabase {
             config: database_instance_config,
             ..Default::default()
         };
@@ -69,16 +207,91 @@ impl PostgressDatabase {
         instance
     }
 
-    fn connect(&mut self) {
+    // Pools are cheap to clone, they share connections
+    fn pool(&self) -> Result<sqlx::Pool<sqlx::Postgres>, std::io::Error> {
+        self.pool
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotConnected, "No connection to database"))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.pool.read().unwrap().is_some()
+    }
+
+    // Retries until the first connection succeeds, the pool restores
+    // connections lost after that by itself
+    pub fn reconnect_routine(&self, process_running: Arc<AtomicBool>, interval: Duration) {
+        while process_running.load(Ordering::Relaxed) && !self.is_connected() {
+            thread::sleep(interval);
+            self.connect();
+        }
+    }
+
+    // Calls on_change with the id of every DeviceConfig row changed in the
+    // database. Changes made while the connection is lost are not notified,
+    // on_resume is called whenever listening starts again after that.
+    pub fn config_changes_routine(&self, process_running: Arc<AtomicBool>, interval: Duration, on_change: impl Fn(u64), on_resume: impl Fn()) {
+        while process_running.load(Ordering::Relaxed) {
+            if self.is_connected() {
+                if let Err(e) = block_on(self.listen_config_changes(&process_running, &on_change, &on_resume)) {
+                    error!("Error listening config changes: {}", e);
+                }
+            }
+            thread::sleep(interval);
+        }
+    }
+
+    async fn listen_config_changes(&self, process_running: &AtomicBool, on_change: &impl Fn(u64), on_resume: &impl Fn()) -> Result<(), std::io::Error> {
+        let mut listener = PgListener::connect_with(&self.pool()?)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        listener
+            .listen(CONFIG_CHANNEL)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        println!("Listening config changes");
+        on_resume();
+        while process_running.load(Ordering::Relaxed) {
+            let notification = listener
+                .try_recv()
+                .await
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+            match notification {
+                Some(notification) => match notification.payload().parse() {
+                    Ok(device_id) => on_change(device_id),
+                    Err(_) => error!("Skip config change of device {}", notification.payload()),
+                },
+                None => {
+                    error!("Lost connection, config changes are missed until it is restored");
+                    // Reconnects and listens again, or fails and the routine retries later
+                    listener
+                        .listen(CONFIG_CHANNEL)
+                        .await
+                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                    debug!("Listening config changes again");
+                    on_resume();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn connect(&self) {
         let connection_string = self.config.make_connection_string();
         let pool = PgPoolOptions::new()
             .max_connections(5)
+            .acquire_timeout(Duration::from_secs(5))
             .connect(&connection_string);
 
         match block_on(pool) {
             Ok(pg_pool) => {
                 println!("Connected to database");
-                self.pool = Some(pg_pool);
+                if let Err(e) = block_on(sqlx::migrate!().run(&pg_pool)) {
+                    println!("Error migrating database: {}", e);
+                }
+                *self.pool.write().unwrap() = Some(pg_pool);
             }
             Err(e) => {
                 println!("Connection string: {}", connection_string);
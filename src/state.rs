@@ -1,14 +1,30 @@
+use be_server::auth::{CounterStore, FrameAuthenticator, KeyStore};
 use be_server::device::HardDevice;
+use be_server::protocol::ProtocolError;
+use be_server::sequence::{SequenceStatus, SequenceTracker};
+use be_server::setpoint::{Setpoint, SetpointEvent, SetpointGuard};
+use be_server::target_store::TargetStore;
+use log::error;
 
 use crate::device;
 use crate::config;
+use std::borrow::Cow;
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
 
 
 #[derive(Clone)]
 pub struct GlobalState {
     metrics_sender: Sender<HardDevice>,
     config: config::ServerConfig,
+    sequence_tracker: Arc<SequenceTracker>,
+    frame_authenticator: Arc<FrameAuthenticator>,
+    setpoint_guard: Arc<SetpointGuard>,
+    target_store: Arc<TargetStore>,
+    events_sender: Option<Sender<SetpointEvent>>,
+    connections_limit: Arc<Semaphore>,
 }
 
 #[derive(Clone)]
@@ -20,21 +36,189 @@ pub struct MqttConfig {
     pub password: String
 }
 
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub cert_path: String,
+    pub key_path: String,
+    pub client_ca_path: Option<String>,
+}
+
 impl GlobalState {
     pub fn new(metrics_sender: Sender<HardDevice>, config: config::ServerConfig) -> GlobalState {
         GlobalState {
             metrics_sender,
+            connections_limit: Arc::new(Semaphore::new(config.max_connections)),
             config,
+            sequence_tracker: Arc::new(SequenceTracker::new()),
+            frame_authenticator: Arc::new(FrameAuthenticator::default()),
+            setpoint_guard: Arc::new(SetpointGuard::default()),
+            target_store: Arc::new(TargetStore::default()),
+            events_sender: None,
+        }
+    }
+    pub fn with_keys(mut self, keys: KeyStore, counters: CounterStore) -> GlobalState {
+        self.frame_authenticator = Arc::new(FrameAuthenticator::new(keys, counters));
+        self
+    }
+    // Parses a frame, informers with pre-shared keys have to sign them
+    pub fn authenticate_frame(&self, frame: &[u8]) -> Result<Vec<HardDevice>, ProtocolError> {
+        self.frame_authenticator.factory(frame)
+    }
+    // Inner frame of a signed one with its signer, see auth.rs
+    pub fn open_frame<'a>(&self, frame: &'a [u8]) -> Result<(Cow<'a, [u8]>, Option<u32>), ProtocolError> {
+        self.frame_authenticator.open(frame)
+    }
+    pub fn authenticate_devices(&self, devices: &[HardDevice], signer: Option<u32>) -> Result<(), ProtocolError> {
+        self.frame_authenticator.check_devices(devices, signer)
+    }
+    pub fn authenticate_informer(&self, informer_id: u32, signer: Option<u32>) -> Result<(), ProtocolError> {
+        self.frame_authenticator.check_informer(informer_id, signer)
+    }
+    pub fn with_setpoint_limits(mut self, setpoint_guard: SetpointGuard) -> GlobalState {
+        self.setpoint_guard = Arc::new(setpoint_guard);
+        self
+    }
+    pub fn with_target_store(mut self, target_store: TargetStore) -> GlobalState {
+        self.target_store = Arc::new(target_store);
+        self
+    }
+    pub fn get_target_store(&self) -> Arc<TargetStore> {
+        self.target_store.clone()
+    }
+    pub fn with_events(mut self, events_sender: Sender<SetpointEvent>) -> GlobalState {
+        self.events_sender = Some(events_sender);
+        self
+    }
+    // Last target sent to the device, otherwise the configured default
+    pub fn fallback_target(&self, device_id: u64) -> Option<Setpoint> {
+        let default_target = match (self.config.default_temperature, self.config.default_humidity) {
+            (Some(temperature), Some(humidity)) => Some(Setpoint { temperature, humidity }),
+            _ => None,
+        };
+        let target = self.target_store.get(device_id).or(default_target)?;
+        self.target_store.mark_fallback(device_id);
+        Some(target)
+    }
+    pub fn needs_device_type(&self) -> bool {
+        self.setpoint_guard.needs_device_type()
+    }
+    // Replaces the device targets with limited ones, false when they are refused.
+    // Limits apply to steps from the last target the device got.
+    pub fn limit_targets(&self, device: &mut HardDevice, device_type: Option<&str>) -> bool {
+        let requested = match (device.get_target_temperature(), device.get_target_humidity()) {
+            (Some(temperature), Some(humidity)) => Setpoint { temperature, humidity },
+            _ => return false,
+        };
+        let previous = self.target_store.get(device.get_id());
+        let (target, events) = match self.setpoint_guard.apply(device.get_id(), device_type, requested, previous) {
+            Ok((target, events)) => (Some(target), events),
+            Err(events) => (None, events),
+        };
+        for event in events {
+            error!("Unsafe target: {}", event.as_json());
+            if let Some(events_sender) = &self.events_sender {
+                let _ = events_sender.send(event);
+            }
+        }
+        match target {
+            Some(target) => {
+                device.set_targets(target.temperature, target.humidity);
+                true
+            }
+            None => false,
+        }
+    }
+    // Targets the informer got become the last known good ones
+    pub fn record_targets(&self, targets: &[(u64, Setpoint)]) {
+        for (device_id, target) in targets {
+            self.target_store.record(*device_id, *target);
         }
     }
     pub fn new_device(&self, device: HardDevice) {
         println!("Send new device data");
         self.metrics_sender.send(device).unwrap();
     }
+    pub fn check_sequence(&self, informer_id: u32, sequence: u32) -> SequenceStatus {
+        self.sequence_tracker.check(informer_id, sequence)
+    }
     pub fn get_tcp_addr(&self) -> String {
         format!("{}:{}", self.config.host, self.config.port)
     }
 
+    pub fn get_psk_file(&self) -> Option<String> {
+        self.config.psk_file.clone()
+    }
+
+    pub fn get_counter_file(&self) -> Option<String> {
+        self.config.counter_file.clone()
+    }
+
+    pub fn get_limits_file(&self) -> Option<String> {
+        self.config.limits_file.clone()
+    }
+
+    pub fn get_targets_file(&self) -> Option<String> {
+        self.config.targets_file.clone()
+    }
+
+    pub fn get_cache_capacity(&self) -> usize {
+        self.config.cache_capacity
+    }
+
+    pub fn get_cache_ttl(&self) -> u64 {
+        self.config.cache_ttl
+    }
+
+    pub fn get_cache_grace(&self) -> u64 {
+        self.config.cache_grace
+    }
+
+    pub fn get_cache_negative_ttl(&self) -> u64 {
+        self.config.cache_negative_ttl
+    }
+
+    pub fn get_reconnect_interval(&self) -> Duration {
+        Duration::from_secs(self.config.sql_reconnect_interval)
+    }
+
+    pub fn get_tls_config(&self) -> Option<TlsConfig> {
+        match (&self.config.tls_cert, &self.config.tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path: cert_path.clone(),
+                key_path: key_path.clone(),
+                client_ca_path: self.config.tls_client_ca.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn get_udp_addr(&self) -> Option<String> {
+        if self.config.udp_port == 0 {
+            return None;
+        }
+        Some(format!("{}:{}", self.config.host, self.config.udp_port))
+    }
+
+    pub fn get_coap_addr(&self) -> Option<String> {
+        if self.config.coap_port == 0 {
+            return None;
+        }
+        Some(format!("{}:{}", self.config.host, self.config.coap_port))
+    }
+
+    pub fn get_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.idle_timeout)
+    }
+
+    pub fn get_max_connections(&self) -> usize {
+        self.config.max_connections
+    }
+
+    // Shared by TCP connections and datagrams in processing
+    pub fn get_connections_limit(&self) -> Arc<Semaphore> {
+        self.connections_limit.clone()
+    }
+
     pub fn is_service_handlers_enabled(&self) -> bool {
         return self.config.service_port != 0;
     }
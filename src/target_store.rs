@@ -0,0 +1,143 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::setpoint::Setpoint;
+
+// Last target sent to every device. It is the step base of the setpoint limits
+// and the fallback while the database is unreachable. With a file the targets
+// survive restarts: a JSON object {"<device id>": {"temperature": .., "humidity": ..}}
+// written on flush, so a crash loses at most the changes since the last one.
+#[derive(Default)]
+pub struct TargetStore {
+    path: Option<PathBuf>,
+    targets: Mutex<HashMap<u64, Setpoint>>,
+    dirty: AtomicBool,
+    fallback_devices: Mutex<HashSet<u64>>,
+}
+
+impl TargetStore {
+    // A missing file is an empty store, it is created on the first flush
+    pub fn open(path: &str) -> io::Result<TargetStore> {
+        let targets = match fs::read_to_string(path) {
+            Ok(content) => TargetStore::parse(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(TargetStore {
+            path: Some(PathBuf::from(path)),
+            targets: Mutex::new(targets),
+            ..Default::default()
+        })
+    }
+
+    fn parse(content: &str) -> io::Result<HashMap<u64, Setpoint>> {
+        let targets: HashMap<String, Setpoint> =
+            serde_json::from_str(content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        targets
+            .into_iter()
+            .map(|(device_id, target)| match device_id.parse() {
+                Ok(device_id) => Ok((device_id, target)),
+                Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bad device id {}", device_id))),
+            })
+            .collect()
+    }
+
+    pub fn get(&self, device_id: u64) -> Option<Setpoint> {
+        self.targets.lock().unwrap().get(&device_id).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.targets.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.lock().unwrap().is_empty()
+    }
+
+    // Target from a config, the device is not on fallback anymore
+    pub fn record(&self, device_id: u64, target: Setpoint) {
+        if self.targets.lock().unwrap().insert(device_id, target) != Some(target) {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        self.fallback_devices.lock().unwrap().remove(&device_id);
+    }
+
+    pub fn mark_fallback(&self, device_id: u64) {
+        self.fallback_devices.lock().unwrap().insert(device_id);
+    }
+
+    // Devices that got a fallback target since their last config
+    pub fn fallback_count(&self) -> usize {
+        self.fallback_devices.lock().unwrap().len()
+    }
+
+    // Writes the file when targets changed, through a temporary file so it is never half written
+    pub fn flush(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let content = {
+            let targets = self.targets.lock().unwrap();
+            let targets: HashMap<String, &Setpoint> = targets.iter().map(|(id, target)| (id.to_string(), target)).collect();
+            serde_json::to_string(&targets)?
+        };
+        let temporary = path.with_extension("tmp");
+        let written = fs::write(&temporary, content).and_then(|_| fs::rename(&temporary, path));
+        if written.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setpoint(temperature: f32, humidity: f32) -> Setpoint {
+        Setpoint { temperature, humidity }
+    }
+
+    #[test]
+    fn test_fallback_count() {
+        let store = TargetStore::default();
+        store.mark_fallback(1201);
+        store.mark_fallback(1202);
+        store.mark_fallback(1201);
+        assert_eq!(store.fallback_count(), 2);
+
+        store.record(1201, setpoint(4.0, 50.0));
+        assert_eq!(store.fallback_count(), 1);
+        assert_eq!(store.get(1201), Some(setpoint(4.0, 50.0)));
+        assert!(store.flush().is_ok(), "Nothing to write without a file");
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("be-server-targets-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let store = TargetStore::open(path).unwrap();
+        assert!(store.is_empty());
+        store.record(1201, setpoint(4.0, 50.0));
+        store.record(70000 << 16 | 3, setpoint(-18.0, 30.0));
+        store.flush().unwrap();
+
+        let reopened = TargetStore::open(path).unwrap();
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.get(70000 << 16 | 3), Some(setpoint(-18.0, 30.0)));
+
+        fs::write(path, "{\"x\": {\"temperature\": 1, \"humidity\": 2}}").unwrap();
+        assert_eq!(TargetStore::open(path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        fs::remove_file(path).unwrap();
+    }
+}
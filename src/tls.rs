@@ -0,0 +1,212 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::device::HardDevice;
+use crate::protocol::ProtocolError;
+
+// Client certificates name the informers a connection may report for,
+// the subject CN and every SAN DNS name of the form `informer-<id>` count.
+pub const INFORMER_NAME_PREFIX: &str = "informer-";
+
+// Mutual TLS asks for a client certificate signed by client_ca_path
+pub fn server_config(cert_path: &str, key_path: &str, client_ca_path: Option<&str>) -> io::Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let builder = ServerConfig::builder();
+    let builder = match client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(client_ca_path)? {
+                roots.add(cert).map_err(invalid_data)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build().map_err(invalid_data)?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(certs, key).map_err(invalid_data)?;
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No certificates in {}", path)));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("No private key in {}", path)))
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+// Informers a mutually authenticated connection may report for
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct InformerIdentity {
+    informer_ids: HashSet<u32>,
+}
+
+impl InformerIdentity {
+    pub fn from_certificate(der: &[u8]) -> InformerIdentity {
+        let informer_ids = certificate_names(der)
+            .iter()
+            .filter_map(|name| name.strip_prefix(INFORMER_NAME_PREFIX)?.parse().ok())
+            .collect();
+        InformerIdentity { informer_ids }
+    }
+
+    pub fn allows(&self, informer_id: u32) -> bool {
+        self.informer_ids.contains(&informer_id)
+    }
+
+    pub fn check(&self, devices: &[HardDevice]) -> Result<(), ProtocolError> {
+        match devices.iter().map(|d| d.get_address().informer_id()).find(|id| !self.allows(*id)) {
+            Some(informer_id) => Err(ProtocolError::ForeignInformer { informer_id }),
+            None => Ok(()),
+        }
+    }
+}
+
+// Subject CN and SAN DNS names of a DER certificate, none if it doesn't parse.
+// The certificate was already verified by rustls.
+pub fn certificate_names(der: &[u8]) -> Vec<String> {
+    let certificate = match X509Certificate::from_der(der) {
+        Ok(([], certificate)) => certificate,
+        _ => return Vec::new(),
+    };
+    let mut names: Vec<String> = certificate.subject().iter_common_name().filter_map(|name| name.as_str().ok()).map(str::to_owned).collect();
+    if let Ok(Some(alt_names)) = certificate.subject_alternative_name() {
+        names.extend(alt_names.value.general_names.iter().filter_map(|name| match name {
+            GeneralName::DNSName(name) => Some(name.to_string()),
+            _ => None,
+        }));
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device_id::DeviceAddress;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    const CA: &str = "tests/certs/ca.pem";
+    const SERVER_CERT: &str = "tests/certs/server.pem";
+    const SERVER_KEY: &str = "tests/certs/server.key";
+    const CLIENT_CERT: &str = "tests/certs/client.pem";
+    const CLIENT_KEY: &str = "tests/certs/client.key";
+
+    #[test]
+    fn test_certificate_names() {
+        let client = load_certs(CLIENT_CERT).unwrap();
+        assert_eq!(certificate_names(&client[0]), vec!["informer-12", "informer-13", "gateway.example.com"]);
+        let server = load_certs(SERVER_CERT).unwrap();
+        assert_eq!(certificate_names(&server[0]), vec!["localhost", "localhost"]);
+        assert_eq!(certificate_names(&[0x30, 0x03, 0x02]), Vec::<String>::new(), "Truncated certificate");
+    }
+
+    #[test]
+    fn test_malformed_certificates() {
+        let client = load_certs(CLIENT_CERT).unwrap()[0].to_vec();
+        assert_eq!(&client[..2], &[0x30, 0x82], "Two length bytes in the client certificate");
+        let no_names = Vec::<String>::new();
+
+        for length in [0, 1, 4, client.len() / 2, client.len() - 1] {
+            assert_eq!(certificate_names(&client[..length]), no_names, "Truncated to {}", length);
+        }
+        let mut trailing = client.clone();
+        trailing.push(0);
+        assert_eq!(certificate_names(&trailing), no_names, "Bytes after the certificate");
+
+        let mut oversized = client.clone();
+        oversized[2..4].copy_from_slice(&[0xFF, 0xFF]);
+        assert_eq!(certificate_names(&oversized), no_names, "Certificate longer than the buffer");
+        let name = client.windows(11).position(|window| window == b"informer-12").unwrap();
+        let mut oversized = client.clone();
+        oversized[name - 1] = 0x7F;
+        assert_eq!(certificate_names(&oversized), vec!["informer-13", "gateway.example.com"], "Name longer than its attribute");
+
+        let mut header = vec![0x30, 0x89];
+        header.extend_from_slice(&[0xFF; 9]);
+        assert_eq!(certificate_names(&header), no_names, "Length wider than usize");
+        assert_eq!(certificate_names(&[0x30, 0x80, 0x00, 0x00]), no_names, "Indefinite length");
+        assert_eq!(certificate_names(&[0x30, 0x84, 0xFF, 0xFF, 0xFF, 0xFF, 0x30]), no_names);
+        assert_eq!(certificate_names(&[0xFF; 64]), no_names);
+    }
+
+    #[test]
+    fn test_identity() {
+        let client = load_certs(CLIENT_CERT).unwrap();
+        let identity = InformerIdentity::from_certificate(&client[0]);
+        assert!(identity.allows(12));
+        assert!(identity.allows(13));
+        assert!(!identity.allows(14));
+
+        let mut frame = vec![12, 1];
+        frame.extend_from_slice(&[0; 8]);
+        let devices = HardDevice::factory(&frame, frame.len()).unwrap();
+        assert_eq!(identity.check(&devices), Ok(()));
+        frame[0] = 14;
+        let devices = HardDevice::factory(&frame, frame.len()).unwrap();
+        assert_eq!(devices[0].get_address(), DeviceAddress::Legacy { informer_id: 14, index: 0 });
+        assert_eq!(identity.check(&devices), Err(ProtocolError::ForeignInformer { informer_id: 14 }));
+    }
+
+    #[test]
+    fn test_bad_paths() {
+        assert!(server_config("tests/certs/missing.pem", SERVER_KEY, None).is_err());
+        assert!(server_config(SERVER_CERT, SERVER_CERT, None).is_err(), "No key in certificate file");
+        assert!(server_config(SERVER_KEY, SERVER_KEY, None).is_err(), "No certificate in key file");
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        let acceptor = TlsAcceptor::from(server_config(SERVER_CERT, SERVER_KEY, Some(CA)).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(socket).await.unwrap();
+            let identity = InformerIdentity::from_certificate(&stream.get_ref().1.peer_certificates().unwrap()[0]);
+            let mut buf = [0; 2];
+            stream.read_exact(&mut buf).await.unwrap();
+            (identity, buf)
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(load_certs(CA).unwrap().remove(0)).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(load_certs(CLIENT_CERT).unwrap(), load_key(CLIENT_KEY).unwrap())
+            .unwrap();
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut stream = TlsConnector::from(Arc::new(client_config))
+            .connect(ServerName::try_from("localhost").unwrap(), socket)
+            .await
+            .unwrap();
+        stream.write_all(&[12, 0]).await.unwrap();
+        stream.flush().await.unwrap();
+
+        let (identity, buf) = server.await.unwrap();
+        assert_eq!(buf, [12, 0]);
+        assert!(identity.allows(12) && identity.allows(13));
+    }
+}
@@ -0,0 +1,242 @@
+use async_std::io as aio;
+use async_std::task;
+use be_server::external::abstract_external::ExternalDatabase;
+use be_server::protocol::{self, Frame, FRAME_TYPE_ACK, FRAME_TYPE_NACK};
+use log::error;
+use std::collections::HashSet;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use crate::session;
+use crate::state::GlobalState;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+// Same frames as the TCP listener, exactly one frame per datagram. Responses
+// go back as a single datagram to the sender address. Datagrams share the
+// connections limit, the ones over it are dropped.
+pub async fn udp_listener_routine(
+    process_running: Arc<AtomicBool>,
+    addr: String,
+    state: GlobalState,
+    service_counter: Arc<AtomicUsize>,
+    external_database: Arc<dyn ExternalDatabase>,
+) -> io::Result<()> {
+    println!("Start UDP listen on: {}", addr);
+    let socket = Arc::new(UdpSocket::bind(addr).await?);
+    println!("UDP Listener started");
+    service_counter.fetch_sub(1, Ordering::Relaxed);
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        if !process_running.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let received = aio::timeout(Duration::from_secs(1), async {
+            socket.recv_from(&mut buf).await
+        })
+        .await;
+        let (n, peer) = match received {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let frame_length = match protocol::frame_length(&buf[..n]) {
+            Some(frame_length) if frame_length <= n => frame_length,
+            _ => {
+                error!("Datagram from {} doesn't contain a complete frame", peer);
+                continue;
+            }
+        };
+        if frame_length < n {
+            error!("Datagram from {} has {} bytes after the frame", peer, n - frame_length);
+        }
+
+        let permit = match state.get_connections_limit().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                error!("Connections limit {} is reached, drop datagram from {}", state.get_max_connections(), peer);
+                continue;
+            }
+        };
+        let frame = buf[..frame_length].to_vec();
+        let datagram_state = state.clone();
+        let datagram_database = external_database.clone();
+        let datagram_socket = socket.clone();
+        task::spawn(async move {
+            // Datagrams have no session, only signed frames acknowledge commands
+            let mut reply = session::process_frame(&frame, None, &mut HashSet::new(), &datagram_state, datagram_database.as_ref()).await;
+            if !reply.signed {
+                limit_reply(&mut reply, frame.len());
+            }
+            if !reply.response.is_empty() {
+                match datagram_socket.send_to(&reply.response, peer).await {
+                    Ok(_) => session::confirm_delivery(&reply, &datagram_state, datagram_database.as_ref()).await,
+                    Err(e) => error!("Error: {}", e),
+                }
+            }
+            drop(permit);
+        });
+    }
+}
+
+// Source addresses of unsigned datagrams may be spoofed. They get no NACKs,
+// and of replies larger than the datagram only the ACK with the targets.
+fn limit_reply(reply: &mut session::Reply, request_length: usize) {
+    let first_frame = match protocol::decode(&reply.response) {
+        Ok(Frame::V2 { frame_type, .. }) => Some(frame_type),
+        _ => None,
+    };
+    if first_frame == Some(FRAME_TYPE_NACK) {
+        *reply = session::Reply::default();
+        return;
+    }
+    if first_frame.is_none() || reply.response.len() <= request_length {
+        return;
+    }
+    match (first_frame, protocol::frame_length(&reply.response)) {
+        (Some(FRAME_TYPE_ACK), Some(ack_length)) => {
+            reply.response.truncate(ack_length);
+            reply.commands.clear();
+        }
+        _ => *reply = session::Reply::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use async_std::task::block_on;
+    use async_trait::async_trait;
+    use be_server::device::HardDevice;
+    use be_server::firmware::FirmwareChunk;
+    use be_server::protocol::{ProtocolError, Response};
+    use clap::Parser;
+    use std::sync::mpsc::channel;
+    use std::{net, thread};
+
+    struct MockExternalDatabase {}
+
+    #[async_trait]
+    impl ExternalDatabase for MockExternalDatabase {
+        async fn get_device_config(&self, device_id: u64) -> Result<String, std::io::Error> {
+            match device_id {
+                1201 => Ok("{\"temperature\": 4.5, \"humidity\": 55.0}".to_owned()),
+                _ => Err(io::Error::new(io::ErrorKind::NotFound, "No config")),
+            }
+        }
+    }
+
+    #[test]
+    fn test_loopback() {
+        let (snd, rcv) = channel::<HardDevice>();
+        let state = GlobalState::new(snd, ServerConfig::parse_from(["be-server", "--uport", "32145"]));
+        let process_running = Arc::new(AtomicBool::new(true));
+        let service_counter = Arc::new(AtomicUsize::new(1));
+
+        let listener_running = process_running.clone();
+        let listener_counter = service_counter.clone();
+        let listener_addr = state.get_udp_addr().unwrap();
+        let listener = thread::spawn(move || {
+            block_on(udp_listener_routine(
+                listener_running,
+                listener_addr,
+                state,
+                listener_counter,
+                Arc::new(MockExternalDatabase {}),
+            ))
+        });
+        while service_counter.load(Ordering::Relaxed) != 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut frame = vec![12, 2];
+        for value in [0.32f32, 0.123, 0.43, 0.98] {
+            frame.extend_from_slice(&value.to_le_bytes());
+        }
+        client.send_to(&frame, "127.0.0.1:32145").unwrap();
+
+        let mut response = [0; 64];
+        let (n, _) = client.recv_from(&mut response).unwrap();
+        assert_eq!(n, 12, "Only device 1201 has config");
+        assert_eq!(u32::from_le_bytes(response[0..4].try_into().unwrap()), 1201);
+        assert_eq!(f32::from_le_bytes(response[4..8].try_into().unwrap()), 55.0);
+        assert_eq!(f32::from_le_bytes(response[8..12].try_into().unwrap()), 4.5);
+
+        let first = rcv.recv_timeout(Duration::from_secs(1)).unwrap();
+        let second = rcv.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(first.get_id(), 1201);
+        assert_eq!(first.get_temperature(), 0.32);
+        assert_eq!(second.get_id(), 1202);
+        assert_eq!(second.get_humidity(), 0.98);
+
+        client.send_to(&[12], "127.0.0.1:32145").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        assert!(client.recv_from(&mut response).is_err(), "Incomplete frame is dropped");
+
+        process_running.store(false, Ordering::Relaxed);
+        listener.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_limit_reply() {
+        let nack = Response::Nack(ProtocolError::BadChecksum).encode();
+        let mut reply = session::Reply::from(nack);
+        limit_reply(&mut reply, 100);
+        assert!(reply.response.is_empty(), "No NACKs to unsigned sources");
+
+        let ack = Response::Ack(vec![vec![0; 12]]).encode();
+        let mut response = ack.clone();
+        response.extend(Response::NoConfig(vec![1202, 1203, 1204]).encode());
+        let reply = || session::Reply { response: response.clone(), commands: vec![7], ..Default::default() };
+        let mut limited = reply();
+        limit_reply(&mut limited, ack.len() + 1);
+        assert_eq!(limited.response, ack, "Only the ACK of a reply larger than the request");
+        assert!(limited.commands.is_empty());
+        let mut limited = reply();
+        limit_reply(&mut limited, response.len());
+        assert_eq!((limited.response, limited.commands), (response.clone(), vec![7]));
+
+        let chunk = Response::FirmwareChunk(FirmwareChunk { version: 5, offset: 0, data: vec![0; 512] }).encode();
+        let mut reply = session::Reply::from(chunk);
+        limit_reply(&mut reply, 28);
+        assert!(reply.response.is_empty());
+
+        let mut reply = session::Reply::from(vec![0; 24]);
+        limit_reply(&mut reply, 18);
+        assert_eq!(reply.response.len(), 24, "v1 targets are kept");
+    }
+
+    #[test]
+    fn test_connections_limit() {
+        let (snd, _rcv) = channel::<HardDevice>();
+        let state = GlobalState::new(snd, ServerConfig::parse_from(["be-server", "--uport", "32148", "--lmaxconn", "0"]));
+        let process_running = Arc::new(AtomicBool::new(true));
+        let service_counter = Arc::new(AtomicUsize::new(1));
+
+        let listener_running = process_running.clone();
+        let listener_counter = service_counter.clone();
+        let listener_addr = state.get_udp_addr().unwrap();
+        let listener = thread::spawn(move || {
+            block_on(udp_listener_routine(listener_running, listener_addr, state, listener_counter, Arc::new(MockExternalDatabase {})))
+        });
+        while service_counter.load(Ordering::Relaxed) != 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let mut frame = vec![12, 1];
+        frame.extend_from_slice(&0.32f32.to_le_bytes());
+        frame.extend_from_slice(&0.43f32.to_le_bytes());
+        client.send_to(&frame, "127.0.0.1:32148").unwrap();
+        assert!(client.recv_from(&mut [0; 64]).is_err(), "Datagram over the limit is dropped");
+
+        process_running.store(false, Ordering::Relaxed);
+        listener.join().unwrap().unwrap();
+    }
+}